@@ -25,6 +25,8 @@ fn main() {
         .include("librsync/src/blake2")
         .define("STDC_HEADERS", Some("1"))
         .define("LIBRSYNC_STATIC_DEFINE", Some("1"))
+        // no lookup statistics, so that signatures are read-only while computing deltas
+        .define("HASHTABLE_NSTATS", None)
         .file("librsync/src/base64.c")
         .file("librsync/src/buf.c")
         .file("librsync/src/checksum.c")
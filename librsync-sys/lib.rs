@@ -49,6 +49,26 @@ pub struct rs_buffers_t {
     pub avail_out: size_t,
 }
 
+#[repr(C)]
+pub struct rs_stats_t {
+    pub op: *const c_char,
+    pub lit_cmds: c_int,
+    pub lit_bytes: rs_long_t,
+    pub lit_cmdbytes: rs_long_t,
+    pub copy_cmds: rs_long_t,
+    pub copy_bytes: rs_long_t,
+    pub copy_cmdbytes: rs_long_t,
+    pub sig_cmds: rs_long_t,
+    pub sig_bytes: rs_long_t,
+    pub false_matches: c_int,
+    pub sig_blocks: rs_long_t,
+    pub block_len: size_t,
+    pub in_bytes: rs_long_t,
+    pub out_bytes: rs_long_t,
+    pub start: time_t,
+    pub end: time_t,
+}
+
 pub type rs_copy_cb = extern "C" fn(
     opaque: *mut c_void,
     pos: rs_long_t,
@@ -60,6 +80,7 @@ pub type rs_trace_fn_t = extern "C" fn(level: rs_loglevel, msg: *const c_char);
 extern "C" {
     pub fn rs_job_iter(job: *mut rs_job_t, buffers: *mut rs_buffers_t) -> rs_result;
     pub fn rs_job_free(job: *mut rs_job_t) -> rs_result;
+    pub fn rs_job_statistics(job: *mut rs_job_t) -> *const rs_stats_t;
 
     pub fn rs_sig_begin(
         new_block_len: size_t,
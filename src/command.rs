@@ -0,0 +1,670 @@
+//! Encoding and decoding of delta commands.
+//!
+//! A delta stream starts with a magic number, followed by a sequence of commands. Each command
+//! starts with an opcode byte, which determines the kind of command and the width of its integer
+//! parameters:
+//!
+//! * `0x00`: end of the delta;
+//! * `0x01..=0x40`: literal data, whose length is the opcode itself;
+//! * `0x41..=0x44`: literal data, whose length follows in 1, 2, 4 or 8 bytes;
+//! * `0x45..=0x54`: copy from the base file, whose offset and length follow in 1, 2, 4 or 8 bytes
+//!   each.
+
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, Read, Write};
+use std::mem;
+
+use crate::netint;
+use crate::{Error, Result, io_err, raw};
+
+const OP_END: u8 = 0x00;
+const OP_LITERAL_MAX_IMMEDIATE: u8 = 0x40;
+const OP_LITERAL_N1: u8 = 0x41;
+const OP_COPY_N1_N1: u8 = 0x45;
+const OP_COPY_N8_N8: u8 = 0x54;
+
+// The possible widths of integer parameters.
+const WIDTHS: [usize; 4] = [1, 2, 4, 8];
+
+// The maximum number of literal bytes shown when displaying an operation.
+pub(crate) const LITERAL_PREVIEW_LEN: usize = 32;
+
+/// An operation of a delta stream.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Op {
+    /// Data to be written as is to the output.
+    Literal(Vec<u8>),
+    /// Data to be copied from the base file.
+    Copy {
+        /// The position in the base file from which copy the data.
+        offset: u64,
+        /// The number of bytes to be copied.
+        len: u64,
+    },
+    /// The end of the delta.
+    End,
+}
+
+impl Display for Op {
+    /// Formats the operation on a single line. Literal data is previewed as escaped text if it is
+    /// mostly printable, or as hex otherwise, and is cut after a few bytes.
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        match *self {
+            Op::Literal(ref data) => LiteralPreview::new(data.len() as u64, data).fmt(fmt),
+            Op::Copy { offset, len } => write!(fmt, "COPY offset={} len={}", offset, len),
+            Op::End => write!(fmt, "END"),
+        }
+    }
+}
+
+// The description of a literal command, given the first bytes of its data.
+pub(crate) struct LiteralPreview<'a> {
+    len: u64,
+    preview: &'a [u8],
+}
+
+impl<'a> LiteralPreview<'a> {
+    // The preview is cut to `LITERAL_PREVIEW_LEN` bytes.
+    pub(crate) fn new(len: u64, preview: &'a [u8]) -> Self {
+        let preview = &preview[..preview.len().min(LITERAL_PREVIEW_LEN)];
+        LiteralPreview { len, preview }
+    }
+}
+
+impl Display for LiteralPreview<'_> {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        write!(fmt, "LITERAL len={} ", self.len)?;
+        let printable = self
+            .preview
+            .iter()
+            .filter(|b| b.is_ascii_graphic() || **b == b' ')
+            .count();
+        if printable * 4 >= self.preview.len() * 3 {
+            write!(fmt, "\"{}\"", self.preview.escape_ascii())?;
+        } else {
+            for b in self.preview {
+                write!(fmt, "{:02x}", b)?;
+            }
+        }
+        if (self.preview.len() as u64) < self.len {
+            write!(fmt, "...")?;
+        }
+        Ok(())
+    }
+}
+
+// A decoded command, without its literal data.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Command {
+    Literal(u64),
+    Copy { offset: u64, len: u64 },
+    End,
+}
+
+// An operation not yet written, because it could be merged with the next ones.
+#[derive(Debug)]
+enum Pending {
+    None,
+    Literal(Vec<u8>),
+    Copy { offset: u64, len: u64 },
+}
+
+/// A decoder of delta streams.
+///
+/// This type takes a `Read` stream for a delta, as produced by `Delta`, and iterates over its
+/// operations. The iteration stops after the `Op::End` operation, or after the first error.
+pub struct DeltaReader<R> {
+    input: R,
+    done: bool,
+}
+
+impl<R: Read> DeltaReader<R> {
+    /// Creates a new decoder, by checking the magic number at the start of the given stream.
+    pub fn new(mut input: R) -> Result<Self> {
+        let magic = netint::read_u32(&mut input).map_err(truncated)?;
+        if magic != raw::RS_DELTA_MAGIC as u32 {
+            return Err(Error::BadMagic);
+        }
+        Ok(DeltaReader { input, done: false })
+    }
+
+    /// Unwraps this decoder, returning the underlying delta stream.
+    pub fn into_inner(self) -> R {
+        self.input
+    }
+
+    pub(crate) fn get_mut(&mut self) -> &mut R {
+        &mut self.input
+    }
+
+    // Decodes the next command. The data of a literal command must be consumed with
+    // `read_literal` before decoding the next one.
+    pub(crate) fn next_command(&mut self) -> Result<Option<Command>> {
+        if self.done {
+            return Ok(None);
+        }
+        let cmd = match self.decode_command() {
+            Ok(cmd) => cmd,
+            Err(e) => {
+                self.done = true;
+                return Err(e);
+            }
+        };
+        if cmd == Command::End {
+            self.done = true;
+        }
+        Ok(Some(cmd))
+    }
+
+    fn decode_command(&mut self) -> Result<Command> {
+        let mut op = [0];
+        self.input.read_exact(&mut op).map_err(truncated)?;
+        let cmd = match op[0] {
+            OP_END => Command::End,
+            op @ 1..=OP_LITERAL_MAX_IMMEDIATE => Command::Literal(u64::from(op)),
+            op @ OP_LITERAL_N1..OP_COPY_N1_N1 => {
+                let width = WIDTHS[usize::from(op - OP_LITERAL_N1)];
+                Command::Literal(netint::read_uint(&mut self.input, width).map_err(truncated)?)
+            }
+            op @ OP_COPY_N1_N1..=OP_COPY_N8_N8 => {
+                let index = usize::from(op - OP_COPY_N1_N1);
+                let offset =
+                    netint::read_uint(&mut self.input, WIDTHS[index / 4]).map_err(truncated)?;
+                let len =
+                    netint::read_uint(&mut self.input, WIDTHS[index % 4]).map_err(truncated)?;
+                Command::Copy { offset, len }
+            }
+            op => return Err(Error::BadCommand(op)),
+        };
+        Ok(cmd)
+    }
+
+    // Reads the data of a literal command.
+    pub(crate) fn read_literal(&mut self, len: u64) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        (&mut self.input).take(len).read_to_end(&mut data)?;
+        if (data.len() as u64) < len {
+            self.done = true;
+            return Err(Error::Truncated);
+        }
+        Ok(data)
+    }
+
+    // Skips the data of a literal command.
+    pub(crate) fn skip_literal(&mut self, len: u64) -> Result<()> {
+        let skipped = io::copy(&mut (&mut self.input).take(len), &mut io::sink())?;
+        if skipped < len {
+            self.done = true;
+            return Err(Error::Truncated);
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Iterator for DeltaReader<R> {
+    type Item = Result<Op>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let op = match self.next_command() {
+            Ok(Some(Command::Literal(len))) => self.read_literal(len).map(Op::Literal),
+            Ok(Some(Command::Copy { offset, len })) => Ok(Op::Copy { offset, len }),
+            Ok(Some(Command::End)) => Ok(Op::End),
+            Ok(None) => return None,
+            Err(e) => Err(e),
+        };
+        Some(op)
+    }
+}
+
+/// A summary of the content of a delta.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DeltaSummary {
+    /// The length of the file produced by applying the delta.
+    pub output_len: u64,
+    /// The highest offset of the base file referenced by copy commands, if any.
+    ///
+    /// The delta can be applied only to base files larger than this offset.
+    pub max_base_offset: Option<u64>,
+    /// The number of literal commands.
+    pub literal_cmds: u64,
+    /// The total length of the literal data.
+    pub literal_bytes: u64,
+    /// The number of copy commands.
+    pub copy_cmds: u64,
+    /// The total length of the data copied from the base file.
+    pub copy_bytes: u64,
+}
+
+impl DeltaSummary {
+    /// Validates a delta and summarizes its content, without the need of the base file.
+    ///
+    /// This function consumes the given delta stream up to its end command. Deltas with a bad
+    /// magic number, ending before the end command, or containing unknown commands, are reported
+    /// as `Error::BadMagic`, `Error::Truncated` and `Error::BadCommand` respectively.
+    pub fn analyze<R: Read + ?Sized>(delta: &mut R) -> Result<Self> {
+        let mut reader = DeltaReader::new(delta)?;
+        let mut summary = DeltaSummary::default();
+        while let Some(cmd) = reader.next_command()? {
+            let len = match cmd {
+                Command::Literal(len) | Command::Copy { len, .. } => len,
+                Command::End => 0,
+            };
+            // the output length bounds the byte counts of both kinds of commands
+            summary.output_len = summary.output_len.checked_add(len).ok_or_else(too_large)?;
+            match cmd {
+                Command::Literal(len) => {
+                    reader.skip_literal(len)?;
+                    summary.literal_cmds += 1;
+                    summary.literal_bytes += len;
+                }
+                Command::Copy { offset, len } => {
+                    if len > 0 {
+                        let last = offset.checked_add(len - 1).ok_or_else(too_large)?;
+                        summary.max_base_offset = summary.max_base_offset.max(Some(last));
+                    }
+                    summary.copy_cmds += 1;
+                    summary.copy_bytes += len;
+                }
+                Command::End => (),
+            }
+        }
+        Ok(summary)
+    }
+}
+
+/// An encoder of delta streams.
+///
+/// This type takes a `Write` stream, and writes to it a delta made of the given literal and copy
+/// operations, which can then be applied by `Patch`. Adjacent literals, and copies of contiguous
+/// ranges of the base file, are merged into single commands, and each command is written with its
+/// smallest encoding.
+///
+/// The delta is complete only after calling `finish`.
+pub struct DeltaWriter<W> {
+    output: W,
+    pending: Pending,
+    written: u64,
+}
+
+impl<W: Write> DeltaWriter<W> {
+    /// Creates a new encoder, by writing the magic number to the given stream.
+    pub fn new(mut output: W) -> Result<Self> {
+        netint::write_u32(&mut output, raw::RS_DELTA_MAGIC as u32)?;
+        Ok(DeltaWriter {
+            output,
+            pending: Pending::None,
+            written: 4,
+        })
+    }
+
+    /// Adds literal data to the delta.
+    pub fn literal(&mut self, data: &[u8]) -> Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        match self.pending {
+            Pending::Literal(ref mut pending) => pending.extend_from_slice(data),
+            _ => {
+                self.flush_pending()?;
+                self.pending = Pending::Literal(data.to_vec());
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds literal data to the delta, by reading exactly `len` bytes from the given stream.
+    ///
+    /// Unlike `literal`, the data is not kept in memory, so this is suitable for large literals.
+    pub fn literal_from<R: Read + ?Sized>(&mut self, input: &mut R, len: u64) -> Result<()> {
+        if len == 0 {
+            return Ok(());
+        }
+        let pending = match mem::replace(&mut self.pending, Pending::None) {
+            Pending::Literal(data) => data,
+            other => {
+                self.pending = other;
+                self.flush_pending()?;
+                Vec::new()
+            }
+        };
+        self.write_literal_header(pending.len() as u64 + len)?;
+        self.output.write_all(&pending)?;
+        let copied = io::copy(&mut input.take(len), &mut self.output)?;
+        if copied < len {
+            return Err(io_err(io::ErrorKind::UnexpectedEof, "literal data ended"));
+        }
+        self.written += pending.len() as u64 + len;
+        Ok(())
+    }
+
+    /// Adds a copy of `len` bytes from the base file, starting at `offset`.
+    pub fn copy(&mut self, offset: u64, len: u64) -> Result<()> {
+        if len == 0 {
+            return Ok(());
+        }
+        match self.pending {
+            Pending::Copy {
+                offset: pending_offset,
+                len: ref mut pending_len,
+            } if pending_offset.checked_add(*pending_len) == Some(offset) => {
+                *pending_len = pending_len.checked_add(len).ok_or_else(too_large)?;
+            }
+            _ => {
+                self.flush_pending()?;
+                self.pending = Pending::Copy { offset, len };
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds the given operation to the delta.
+    ///
+    /// `Op::End` is ignored, since the end of the delta is written by `finish`.
+    pub fn write_op(&mut self, op: &Op) -> Result<()> {
+        match *op {
+            Op::Literal(ref data) => self.literal(data),
+            Op::Copy { offset, len } => self.copy(offset, len),
+            Op::End => Ok(()),
+        }
+    }
+
+    /// Completes the delta, and returns the total number of bytes written.
+    ///
+    /// No other operations should be added after calling this.
+    pub fn finish(&mut self) -> Result<u64> {
+        self.flush_pending()?;
+        self.output.write_all(&[OP_END])?;
+        self.written += 1;
+        self.output.flush()?;
+        Ok(self.written)
+    }
+
+    /// Unwraps this encoder, returning the underlying stream.
+    pub fn into_inner(self) -> W {
+        self.output
+    }
+
+    fn flush_pending(&mut self) -> Result<()> {
+        match mem::replace(&mut self.pending, Pending::None) {
+            Pending::None => (),
+            Pending::Literal(data) => {
+                self.write_literal_header(data.len() as u64)?;
+                self.output.write_all(&data)?;
+                self.written += data.len() as u64;
+            }
+            Pending::Copy { offset, len } => {
+                let offset_index = width_index(offset);
+                let len_index = width_index(len);
+                let op = OP_COPY_N1_N1 + (offset_index * 4 + len_index) as u8;
+                self.output.write_all(&[op])?;
+                netint::write_uint(&mut self.output, offset, WIDTHS[offset_index])?;
+                netint::write_uint(&mut self.output, len, WIDTHS[len_index])?;
+                self.written += copy_cmd_len(offset, len);
+            }
+        }
+        Ok(())
+    }
+
+    fn write_literal_header(&mut self, len: u64) -> Result<()> {
+        if len <= u64::from(OP_LITERAL_MAX_IMMEDIATE) {
+            self.output.write_all(&[len as u8])?;
+        } else {
+            let index = width_index(len);
+            self.output.write_all(&[OP_LITERAL_N1 + index as u8])?;
+            netint::write_uint(&mut self.output, len, WIDTHS[index])?;
+        }
+        self.written += literal_cmd_len(len);
+        Ok(())
+    }
+}
+
+// Reports an unexpected end of the delta with a dedicated error.
+pub(crate) fn truncated(e: io::Error) -> Error {
+    if e.kind() == io::ErrorKind::UnexpectedEof {
+        Error::Truncated
+    } else {
+        Error::Io(e)
+    }
+}
+
+pub(crate) fn too_large() -> Error {
+    io_err(io::ErrorKind::InvalidData, "delta output too large")
+}
+
+// Returns the encoded length of a literal command, without its data.
+pub(crate) fn literal_cmd_len(len: u64) -> u64 {
+    if len <= u64::from(OP_LITERAL_MAX_IMMEDIATE) {
+        1
+    } else {
+        1 + WIDTHS[width_index(len)] as u64
+    }
+}
+
+// Returns the encoded length of a copy command.
+pub(crate) fn copy_cmd_len(offset: u64, len: u64) -> u64 {
+    1 + (WIDTHS[width_index(offset)] + WIDTHS[width_index(len)]) as u64
+}
+
+// Returns the index of the smallest width able to represent the given value.
+fn width_index(value: u64) -> usize {
+    WIDTHS
+        .iter()
+        .position(|&w| w == 8 || value >> (w * 8) == 0)
+        .unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::{DELTA, decode, encode};
+    use std::io::Cursor;
+
+    #[test]
+    fn read() {
+        let ops = decode(&DELTA).unwrap();
+        assert_eq!(
+            ops,
+            vec![
+                Op::Literal(b"this is another ".to_vec()),
+                Op::Copy {
+                    offset: 10,
+                    len: 19
+                },
+                Op::End,
+            ]
+        );
+    }
+
+    #[test]
+    fn read_widths() {
+        let mut delta = DELTA[..4].to_vec();
+        delta.extend_from_slice(&[0x42, 0x00, 0x02, b'a', b'b']);
+        delta.extend_from_slice(&[0x4e, 0x01, 0x00, 0x00, 0x00, 0x01, 0x00]);
+        delta.extend_from_slice(&[0x54, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 9]);
+        delta.push(0x00);
+        let ops = decode(&delta).unwrap();
+        assert_eq!(
+            ops,
+            vec![
+                Op::Literal(b"ab".to_vec()),
+                Op::Copy {
+                    offset: 1 << 24,
+                    len: 1 << 8
+                },
+                Op::Copy {
+                    offset: 1 << 32,
+                    len: 9
+                },
+                Op::End,
+            ]
+        );
+    }
+
+    #[test]
+    fn write() {
+        let ops = decode(&DELTA).unwrap();
+        assert_eq!(encode(&ops), DELTA);
+    }
+
+    #[test]
+    fn write_merged() {
+        let delta = encode(&[
+            Op::Literal(b"ab".to_vec()),
+            Op::Literal(Vec::new()),
+            Op::Literal(b"cd".to_vec()),
+            Op::Copy { offset: 0, len: 5 },
+            Op::Copy { offset: 5, len: 5 },
+            Op::Copy { offset: 20, len: 1 },
+        ]);
+        assert_eq!(
+            decode(&delta).unwrap(),
+            vec![
+                Op::Literal(b"abcd".to_vec()),
+                Op::Copy { offset: 0, len: 10 },
+                Op::Copy { offset: 20, len: 1 },
+                Op::End,
+            ]
+        );
+    }
+
+    #[test]
+    fn write_overflow() {
+        // copies wrapping around the end of the base file must not be merged
+        let delta = encode(&[
+            Op::Copy {
+                offset: u64::MAX,
+                len: 5,
+            },
+            Op::Copy { offset: 4, len: 1 },
+        ]);
+        assert_eq!(
+            decode(&delta).unwrap(),
+            vec![
+                Op::Copy {
+                    offset: u64::MAX,
+                    len: 5
+                },
+                Op::Copy { offset: 4, len: 1 },
+                Op::End,
+            ]
+        );
+
+        let mut writer = DeltaWriter::new(Vec::new()).unwrap();
+        writer.copy(u64::MAX - 4, 4).unwrap();
+        assert!(writer.copy(u64::MAX, u64::MAX).is_err());
+    }
+
+    #[test]
+    fn write_widths() {
+        let ops = vec![
+            Op::Literal(vec![7; 300]),
+            Op::Copy {
+                offset: 1 << 32,
+                len: 1,
+            },
+            Op::Literal(vec![1; 64]),
+            Op::Copy {
+                offset: 70000,
+                len: 1 << 40,
+            },
+            Op::End,
+        ];
+        let delta = encode(&ops);
+        assert_eq!(delta[4..7], [0x42, 0x01, 0x2c]);
+        assert_eq!(delta[307], 0x51);
+        assert_eq!(delta[317], 0x40);
+        assert_eq!(delta[382], 0x50);
+        assert_eq!(decode(&delta).unwrap(), ops);
+    }
+
+    #[test]
+    fn write_literal_from() {
+        let mut writer = DeltaWriter::new(Vec::new()).unwrap();
+        writer.literal(b"this is ").unwrap();
+        writer
+            .literal_from(&mut Cursor::new(b"another "), 8)
+            .unwrap();
+        writer.copy(10, 19).unwrap();
+        writer.finish().unwrap();
+        assert_eq!(writer.into_inner(), DELTA);
+
+        let mut writer = DeltaWriter::new(Vec::new()).unwrap();
+        assert!(writer.literal_from(&mut Cursor::new(b"short"), 8).is_err());
+    }
+
+    #[test]
+    fn read_invalid() {
+        let mut delta = DELTA;
+        delta[3] = 0x37;
+        assert!(matches!(decode(&delta), Err(Error::BadMagic)));
+        assert!(matches!(decode(&DELTA[..2]), Err(Error::Truncated)));
+        assert!(matches!(decode(&DELTA[..10]), Err(Error::Truncated)));
+        assert!(matches!(decode(&DELTA[..23]), Err(Error::Truncated)));
+        assert!(matches!(decode(&DELTA[..24]), Err(Error::Truncated)));
+        let mut delta = DELTA;
+        delta[21] = 0x60;
+        assert!(matches!(decode(&delta), Err(Error::BadCommand(0x60))));
+    }
+
+    #[test]
+    fn display() {
+        let ops = decode(&DELTA).unwrap();
+        assert_eq!(ops[0].to_string(), "LITERAL len=16 \"this is another \"");
+        assert_eq!(ops[1].to_string(), "COPY offset=10 len=19");
+        assert_eq!(ops[2].to_string(), "END");
+        let op = Op::Literal(vec![0, 1, 0xfe, b'a']);
+        assert_eq!(op.to_string(), "LITERAL len=4 0001fe61");
+        let op = Op::Literal(vec![b'a'; 40]);
+        assert_eq!(
+            op.to_string(),
+            format!("LITERAL len=40 \"{}\"...", "a".repeat(32))
+        );
+        let op = Op::Literal(b"tab\tquote\"".to_vec());
+        assert_eq!(op.to_string(), "LITERAL len=10 \"tab\\tquote\\\"\"");
+    }
+
+    #[test]
+    fn summary() {
+        let summary = DeltaSummary::analyze(&mut Cursor::new(DELTA)).unwrap();
+        assert_eq!(
+            summary,
+            DeltaSummary {
+                output_len: 35,
+                max_base_offset: Some(28),
+                literal_cmds: 1,
+                literal_bytes: 16,
+                copy_cmds: 1,
+                copy_bytes: 19,
+            }
+        );
+
+        let mut delta = DELTA;
+        delta[0] = 0;
+        let res = DeltaSummary::analyze(&mut Cursor::new(delta));
+        assert!(matches!(res, Err(Error::BadMagic)));
+        let res = DeltaSummary::analyze(&mut Cursor::new(&DELTA[..12]));
+        assert!(matches!(res, Err(Error::Truncated)));
+        let mut delta = DELTA;
+        delta[4] = 0xff;
+        let res = DeltaSummary::analyze(&mut Cursor::new(delta));
+        assert!(matches!(res, Err(Error::BadCommand(0xff))));
+    }
+
+    #[test]
+    fn summary_overflow() {
+        let delta = encode(&[
+            Op::Copy {
+                offset: 0,
+                len: u64::MAX,
+            },
+            Op::Copy {
+                offset: 100,
+                len: 5,
+            },
+            Op::End,
+        ]);
+        let res = DeltaSummary::analyze(&mut Cursor::new(delta));
+        assert!(matches!(res, Err(Error::Io(ref e)) if e.kind() == io::ErrorKind::InvalidData));
+    }
+}
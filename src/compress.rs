@@ -0,0 +1,302 @@
+//! Compression of delta and signature streams.
+//!
+//! A compressed stream starts with a magic number and a byte identifying the compression method,
+//! followed by the stream compressed with that method, up to its end. Decompression is
+//! transparent: `Delta` and `Patch` detect compressed inputs by their magic number.
+
+use std::io::{self, BufRead, Read};
+
+#[cfg(feature = "gzip")]
+use flate2::bufread::{GzDecoder, GzEncoder};
+#[cfg(any(feature = "zstd", feature = "gzip"))]
+use std::io::BufReader;
+
+use crate::{Result, io_err, read_full};
+
+const COMPRESSED_MAGIC: u32 = 0x7273_0c36;
+const METHOD_GZIP: u8 = 1;
+const METHOD_ZSTD: u8 = 2;
+
+/// A compression method.
+#[cfg(any(feature = "zstd", feature = "gzip"))]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Compression {
+    /// Gzip compression, with the given level between 0 and 9.
+    #[cfg(feature = "gzip")]
+    Gzip(u32),
+    /// Zstandard compression, with the given level between 1 and 22.
+    #[cfg(feature = "zstd")]
+    Zstd(i32),
+}
+
+/// A struct to compress a delta or a signature.
+///
+/// This type takes a `Read` stream, usually a `Delta` or a `Signature`, and implements another
+/// `Read` stream from which get the compressed result, framed so that `Delta` and `Patch` can
+/// recognize it.
+#[cfg(any(feature = "zstd", feature = "gzip"))]
+pub struct Compressed<R> {
+    header: [u8; 5],
+    pos: usize,
+    encoder: Encoder<R>,
+}
+
+#[cfg(any(feature = "zstd", feature = "gzip"))]
+enum Encoder<R> {
+    #[cfg(feature = "gzip")]
+    Gzip(GzEncoder<BufReader<R>>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::stream::read::Encoder<'static, BufReader<R>>),
+}
+
+#[cfg(any(feature = "zstd", feature = "gzip"))]
+impl<R: Read> Compressed<R> {
+    /// Creates a new compressed stream, by using the given compression method.
+    pub fn new(input: R, compression: Compression) -> Result<Self> {
+        let (method, encoder) = match compression {
+            #[cfg(feature = "gzip")]
+            Compression::Gzip(level) => {
+                let level = flate2::Compression::new(level.min(9));
+                let encoder = GzEncoder::new(BufReader::new(input), level);
+                (METHOD_GZIP, Encoder::Gzip(encoder))
+            }
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(level) => {
+                let encoder = zstd::stream::read::Encoder::new(input, level)?;
+                (METHOD_ZSTD, Encoder::Zstd(encoder))
+            }
+        };
+        let mut header = [0; 5];
+        header[..4].copy_from_slice(&COMPRESSED_MAGIC.to_be_bytes());
+        header[4] = method;
+        Ok(Compressed {
+            header,
+            pos: 0,
+            encoder,
+        })
+    }
+
+    /// Unwraps this stream, returning the underlying input stream.
+    pub fn into_inner(self) -> R {
+        match self.encoder {
+            #[cfg(feature = "gzip")]
+            Encoder::Gzip(encoder) => encoder.into_inner().into_inner(),
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(encoder) => encoder.finish().into_inner(),
+        }
+    }
+}
+
+#[cfg(any(feature = "zstd", feature = "gzip"))]
+impl<R: Read> Read for Compressed<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos < self.header.len() {
+            let n = buf.len().min(self.header.len() - self.pos);
+            buf[..n].copy_from_slice(&self.header[self.pos..self.pos + n]);
+            self.pos += n;
+            return Ok(n);
+        }
+        match self.encoder {
+            #[cfg(feature = "gzip")]
+            Encoder::Gzip(ref mut encoder) => encoder.read(buf),
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(ref mut encoder) => encoder.read(buf),
+        }
+    }
+}
+
+// A `BufRead` wrapper replaying some bytes already read from the inner stream.
+pub struct Prefixed<R> {
+    inner: R,
+    prefix: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read> Prefixed<R> {
+    // Reads up to `len` bytes from the given stream, to be inspected before replaying them.
+    pub fn new(mut inner: R, len: usize) -> io::Result<Self> {
+        let mut prefix = vec![0; len];
+        let read = read_full(&mut inner, &mut prefix)?;
+        prefix.truncate(read);
+        Ok(Self::with_prefix(inner, prefix))
+    }
+
+    // Replays the given bytes before the rest of the given stream.
+    pub fn with_prefix(inner: R, prefix: Vec<u8>) -> Self {
+        Prefixed {
+            inner,
+            prefix,
+            pos: 0,
+        }
+    }
+
+    // Returns the bytes not yet replayed.
+    pub fn prefix(&self) -> &[u8] {
+        &self.prefix[self.pos..]
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: BufRead> Read for Prefixed<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = {
+            let data = self.fill_buf()?;
+            let n = data.len().min(buf.len());
+            buf[..n].copy_from_slice(&data[..n]);
+            n
+        };
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl<R: BufRead> BufRead for Prefixed<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.pos < self.prefix.len() {
+            return Ok(&self.prefix[self.pos..]);
+        }
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        if self.pos < self.prefix.len() {
+            self.pos += amt;
+        } else {
+            self.inner.consume(amt);
+        }
+    }
+}
+
+// A stream, decompressed if it starts with the magic number of compressed streams.
+pub enum Decompressed<R> {
+    Plain(Prefixed<R>),
+    #[cfg(feature = "gzip")]
+    Gzip(BufReader<GzDecoder<R>>),
+    #[cfg(feature = "zstd")]
+    Zstd(BufReader<zstd::stream::read::Decoder<'static, R>>),
+}
+
+impl<R: BufRead> Decompressed<R> {
+    pub fn new(inner: R) -> Result<Self> {
+        let inner = Prefixed::new(inner, 5)?;
+        let prefix = inner.prefix();
+        if prefix.len() < 5 || prefix[..4] != COMPRESSED_MAGIC.to_be_bytes() {
+            return Ok(Decompressed::Plain(inner));
+        }
+        match prefix[4] {
+            #[cfg(feature = "gzip")]
+            METHOD_GZIP => {
+                let decoder = GzDecoder::new(inner.into_inner());
+                Ok(Decompressed::Gzip(BufReader::new(decoder)))
+            }
+            #[cfg(feature = "zstd")]
+            METHOD_ZSTD => {
+                let decoder = zstd::stream::read::Decoder::with_buffer(inner.into_inner())?;
+                Ok(Decompressed::Zstd(BufReader::new(decoder)))
+            }
+            // the compression features are disabled
+            #[cfg(not(feature = "gzip"))]
+            METHOD_GZIP => Err(crate::Error::Unimplemented),
+            #[cfg(not(feature = "zstd"))]
+            METHOD_ZSTD => Err(crate::Error::Unimplemented),
+            _ => Err(io_err(
+                io::ErrorKind::InvalidData,
+                "unknown compression method",
+            )),
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        match self {
+            Decompressed::Plain(inner) => inner.into_inner(),
+            #[cfg(feature = "gzip")]
+            Decompressed::Gzip(inner) => inner.into_inner().into_inner(),
+            #[cfg(feature = "zstd")]
+            Decompressed::Zstd(inner) => inner.into_inner().finish(),
+        }
+    }
+}
+
+impl<R: BufRead> Read for Decompressed<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Decompressed::Plain(ref mut inner) => inner.read(buf),
+            #[cfg(feature = "gzip")]
+            Decompressed::Gzip(ref mut inner) => inner.read(buf),
+            #[cfg(feature = "zstd")]
+            Decompressed::Zstd(ref mut inner) => inner.read(buf),
+        }
+    }
+}
+
+impl<R: BufRead> BufRead for Decompressed<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        match *self {
+            Decompressed::Plain(ref mut inner) => inner.fill_buf(),
+            #[cfg(feature = "gzip")]
+            Decompressed::Gzip(ref mut inner) => inner.fill_buf(),
+            #[cfg(feature = "zstd")]
+            Decompressed::Zstd(ref mut inner) => inner.fill_buf(),
+        }
+    }
+
+    fn consume(&mut self, amt: usize) {
+        match *self {
+            Decompressed::Plain(ref mut inner) => inner.consume(amt),
+            #[cfg(feature = "gzip")]
+            Decompressed::Gzip(ref mut inner) => inner.consume(amt),
+            #[cfg(feature = "zstd")]
+            Decompressed::Zstd(ref mut inner) => inner.consume(amt),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const DATA: &[u8] = b"some data to be compressed, some data to be compressed";
+
+    fn decompressed(data: &[u8]) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+        Decompressed::new(data)?.read_to_end(&mut out)?;
+        Ok(out)
+    }
+
+    #[test]
+    fn plain() {
+        assert_eq!(decompressed(DATA).unwrap(), DATA);
+        assert_eq!(decompressed(b"rs").unwrap(), b"rs");
+        assert_eq!(decompressed(b"").unwrap(), b"");
+    }
+
+    #[test]
+    fn unknown_method() {
+        let mut data = COMPRESSED_MAGIC.to_be_bytes().to_vec();
+        data.push(0xff);
+        assert!(decompressed(&data).is_err());
+    }
+
+    #[cfg(any(feature = "zstd", feature = "gzip"))]
+    #[test]
+    fn round_trip() {
+        let methods = [
+            #[cfg(feature = "gzip")]
+            Compression::Gzip(6),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(3),
+        ];
+        for &method in &methods {
+            let mut compressed = Vec::new();
+            Compressed::new(DATA, method)
+                .unwrap()
+                .read_to_end(&mut compressed)
+                .unwrap();
+            assert_eq!(compressed[..4], COMPRESSED_MAGIC.to_be_bytes());
+            assert_eq!(decompressed(&compressed).unwrap(), DATA);
+        }
+    }
+}
@@ -0,0 +1,128 @@
+//! Human-readable dumps of deltas and signatures.
+//!
+//! Provides functions that print the content of a delta or a signature stream, one line per
+//! command or block. They are meant for inspection and debugging, and the format of their output
+//! is not stable.
+
+use std::io::{Read, Write};
+
+use crate::command::{Command, LITERAL_PREVIEW_LEN, LiteralPreview, too_large};
+use crate::{DeltaReader, Op, Result, SignatureBlocks};
+
+/// Writes a human-readable description of a delta to the given output.
+///
+/// The delta is printed as one line per command, prefixed by the position in the patched file at
+/// which the command writes its data. Literal data is shown as a short preview, as escaped text
+/// or as hex.
+pub fn delta<R, W>(input: &mut R, output: &mut W) -> Result<()>
+where
+    R: Read + ?Sized,
+    W: Write + ?Sized,
+{
+    let mut reader = DeltaReader::new(input)?;
+    let mut pos = 0u64;
+    while let Some(cmd) = reader.next_command()? {
+        let len = match cmd {
+            Command::Literal(len) => {
+                // only the previewed bytes of the literal are kept in memory
+                let kept = len.min(LITERAL_PREVIEW_LEN as u64);
+                let preview = reader.read_literal(kept)?;
+                reader.skip_literal(len - kept)?;
+                writeln!(output, "@{} {}", pos, LiteralPreview::new(len, &preview))?;
+                len
+            }
+            Command::Copy { offset, len } => {
+                writeln!(output, "@{} {}", pos, Op::Copy { offset, len })?;
+                len
+            }
+            Command::End => {
+                writeln!(output, "@{} {}", pos, Op::End)?;
+                0
+            }
+        };
+        pos = pos.checked_add(len).ok_or_else(too_large)?;
+    }
+    Ok(())
+}
+
+/// Writes a human-readable description of a signature to the given output.
+///
+/// The signature is printed as its header, followed by one line per block, with the weak and
+/// strong checksums in hex.
+pub fn signature<R, W>(input: &mut R, output: &mut W) -> Result<()>
+where
+    R: Read + ?Sized,
+    W: Write + ?Sized,
+{
+    let blocks = SignatureBlocks::new(input)?;
+    writeln!(output, "{}", blocks.header())?;
+    for block in blocks {
+        writeln!(output, "{}", block?)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Error;
+    use crate::test_util::{DELTA, SIG, encode};
+    use std::io::{self, Cursor};
+    use std::str::from_utf8;
+
+    #[test]
+    fn dump_delta() {
+        let mut out = Vec::new();
+        delta(&mut Cursor::new(DELTA), &mut out).unwrap();
+        assert_eq!(
+            from_utf8(&out).unwrap(),
+            "@0 LITERAL len=16 \"this is another \"\n\
+             @16 COPY offset=10 len=19\n\
+             @35 END\n"
+        );
+
+        let res = delta(&mut Cursor::new(&DELTA[..10]), &mut Vec::new());
+        assert!(matches!(res, Err(Error::Truncated)));
+    }
+
+    #[test]
+    fn dump_long_literal() {
+        let data = vec![b'x'; 100];
+        let mut out = Vec::new();
+        delta(&mut Cursor::new(encode(&[Op::Literal(data)])), &mut out).unwrap();
+        assert_eq!(
+            from_utf8(&out).unwrap(),
+            format!("@0 LITERAL len=100 \"{}\"...\n@100 END\n", "x".repeat(32))
+        );
+    }
+
+    #[test]
+    fn dump_overflow() {
+        let delta_data = encode(&[
+            Op::Copy {
+                offset: 0,
+                len: u64::MAX,
+            },
+            Op::Copy {
+                offset: 100,
+                len: 5,
+            },
+            Op::End,
+        ]);
+        let res = delta(&mut Cursor::new(delta_data), &mut Vec::new());
+        assert!(matches!(res, Err(Error::Io(ref e)) if e.kind() == io::ErrorKind::InvalidData));
+    }
+
+    #[test]
+    fn dump_signature() {
+        let mut out = Vec::new();
+        signature(&mut Cursor::new(SIG), &mut out).unwrap();
+        assert_eq!(
+            from_utf8(&out).unwrap(),
+            "MD4 signature, block_len=10 strong_len=5\n\
+             block 0 weak=1b21048b strong=ad3cbd1909\n\
+             block 1 weak=1d1b04f0 strong=9d1f6431de\n\
+             block 2 weak=15f40487 strong=6096195039\n"
+        );
+    }
+}
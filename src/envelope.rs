@@ -0,0 +1,432 @@
+//! Integrity-checked envelope around delta streams.
+//!
+//! An envelope wraps a plain delta stream between a header and a trailer:
+//!
+//! * header: the envelope magic number, followed by the 32 bytes fingerprint of the signature
+//!   used to compute the delta;
+//! * body: the delta stream, as produced by librsync;
+//! * trailer: the length of the new file in 8 bytes, followed by its 32 bytes BLAKE2b hash.
+//!
+//! All the integers are in network byte order. Fingerprints are BLAKE2b hashes of the whole
+//! signature streams.
+
+use std::io::{self, BufRead, Read};
+
+use blake2b_simd::{Params, State};
+
+use crate::compress::Prefixed;
+use crate::job::JobDriver;
+use crate::netint;
+use crate::{Error, Result};
+
+/// The length of signature fingerprints and file hashes, in bytes.
+pub const HASH_LEN: usize = 32;
+
+const ENVELOPE_MAGIC: u32 = 0x7273_0336;
+const TRAILER_LEN: usize = 8 + HASH_LEN;
+
+/// The number of bytes added by an envelope to a delta.
+pub const OVERHEAD: u64 = (4 + HASH_LEN + TRAILER_LEN) as u64;
+
+// Returns a new BLAKE2b hasher, with the length used by envelopes.
+pub fn hasher() -> State {
+    Params::new().hash_length(HASH_LEN).to_state()
+}
+
+fn finalize(state: &State) -> [u8; HASH_LEN] {
+    let mut hash = [0; HASH_LEN];
+    hash.copy_from_slice(state.finalize().as_bytes());
+    hash
+}
+
+// A `BufRead` wrapper hashing the data consumed from the inner stream.
+pub struct HashRead<R> {
+    inner: R,
+    state: Option<State>,
+    len: u64,
+    // the data taken from the inner stream while hashing, not consumed yet
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: BufRead> HashRead<R> {
+    pub fn new(inner: R, hash: bool) -> Self {
+        HashRead {
+            inner,
+            state: if hash { Some(hasher()) } else { None },
+            len: 0,
+            buf: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    // Returns the hash of the data consumed so far, if hashing is enabled.
+    pub fn hash(&self) -> Option<[u8; HASH_LEN]> {
+        self.state.as_ref().map(finalize)
+    }
+
+    // Stops hashing the data consumed from now on.
+    pub fn stop_hashing(&mut self) {
+        self.state = None;
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: BufRead> Read for HashRead<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = {
+            let data = self.fill_buf()?;
+            let n = data.len().min(buf.len());
+            buf[..n].copy_from_slice(&data[..n]);
+            n
+        };
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl<R: BufRead> BufRead for HashRead<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.pos == self.buf.len() {
+            if self.state.is_none() {
+                return self.inner.fill_buf();
+            }
+            // keep the data, so that it can be hashed once consumed
+            let data = self.inner.fill_buf()?;
+            self.buf.clear();
+            self.buf.extend_from_slice(data);
+            self.pos = 0;
+            let n = data.len();
+            self.inner.consume(n);
+        }
+        Ok(&self.buf[self.pos..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        if self.pos == self.buf.len() {
+            self.inner.consume(amt);
+        } else {
+            if let Some(ref mut state) = self.state {
+                state.update(&self.buf[self.pos..self.pos + amt]);
+            }
+            self.pos += amt;
+        }
+        self.len += amt as u64;
+    }
+}
+
+// The envelope written around the output of a delta job.
+pub struct EnvelopeWriter {
+    pending: Vec<u8>,
+    pos: usize,
+    body_done: bool,
+}
+
+impl EnvelopeWriter {
+    pub fn new(fingerprint: &[u8; HASH_LEN]) -> Self {
+        let mut header = ENVELOPE_MAGIC.to_be_bytes().to_vec();
+        header.extend_from_slice(fingerprint);
+        EnvelopeWriter {
+            pending: header,
+            pos: 0,
+            body_done: false,
+        }
+    }
+
+    // Reads the enveloped output of the given delta job, whose input must be hashed.
+    pub fn read<R: BufRead>(
+        &mut self,
+        driver: &mut JobDriver<HashRead<R>>,
+        buf: &mut [u8],
+    ) -> io::Result<usize> {
+        loop {
+            if self.pos < self.pending.len() {
+                let n = buf.len().min(self.pending.len() - self.pos);
+                buf[..n].copy_from_slice(&self.pending[self.pos..self.pos + n]);
+                self.pos += n;
+                return Ok(n);
+            }
+            if self.body_done || buf.is_empty() {
+                return Ok(0);
+            }
+            let n = driver.read(buf)?;
+            if n > 0 {
+                return Ok(n);
+            }
+            // the delta is complete, append the trailer
+            let input = driver.get_ref();
+            let hash = input.hash().expect("enveloped delta input must be hashed");
+            self.pending = input.len.to_be_bytes().to_vec();
+            self.pending.extend_from_slice(&hash);
+            self.pos = 0;
+            self.body_done = true;
+        }
+    }
+}
+
+/// The input of a patch job, optionally wrapped in an envelope.
+///
+/// In an envelope, the trailer is held back from the patch job, and is checked against the
+/// patched output once the job is complete.
+pub struct DeltaInput<D> {
+    inner: Prefixed<D>,
+    envelope: Option<Box<EnvelopeReader>>,
+}
+
+struct EnvelopeReader {
+    fingerprint: [u8; HASH_LEN],
+    buf: Vec<u8>,
+    pos: usize,
+    eof: bool,
+    state: State,
+    out_len: u64,
+    checked: bool,
+}
+
+impl<D: BufRead> DeltaInput<D> {
+    // Wraps the given delta, by detecting whether it is enveloped or not.
+    pub fn new(inner: D) -> Result<Self> {
+        let inner = Prefixed::new(inner, 4 + HASH_LEN)?;
+        let header = inner.prefix();
+        if header.len() < 4 || header[..4] != ENVELOPE_MAGIC.to_be_bytes() {
+            return Ok(DeltaInput {
+                inner,
+                envelope: None,
+            });
+        }
+        if header.len() < 4 + HASH_LEN {
+            return Err(Error::Truncated);
+        }
+        let mut fingerprint = [0; HASH_LEN];
+        fingerprint.copy_from_slice(&header[4..]);
+        Ok(DeltaInput {
+            inner: Prefixed::new(inner.into_inner(), 0)?,
+            envelope: Some(Box::new(EnvelopeReader {
+                fingerprint,
+                buf: Vec::new(),
+                pos: 0,
+                eof: false,
+                state: hasher(),
+                out_len: 0,
+                checked: false,
+            })),
+        })
+    }
+
+    // Returns the fingerprint of the signature recorded in the envelope, if any.
+    pub fn fingerprint(&self) -> Option<[u8; HASH_LEN]> {
+        self.envelope.as_ref().map(|e| e.fingerprint)
+    }
+
+    // Accounts for some patched output, and checks the trailer at the end of the output.
+    pub fn check_output(&mut self, out: &[u8], eof: bool) -> io::Result<()> {
+        match self.envelope {
+            Some(ref mut env) if !env.checked => {
+                env.state.update(out);
+                env.out_len += out.len() as u64;
+                if !eof {
+                    return Ok(());
+                }
+                env.checked = true;
+            }
+            _ => return Ok(()),
+        }
+        // make sure the input has been read up to the end
+        if !self.fill_buf()?.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unexpected data after the end of the delta",
+            ));
+        }
+        let env = self.envelope.as_ref().expect("envelope checked above");
+        let rest = &env.buf[env.pos..];
+        if rest.len() != TRAILER_LEN {
+            return Err(io::Error::other(Error::Truncated));
+        }
+        let len = netint::read_uint(&mut &rest[..8], 8)?;
+        if len != env.out_len || rest[8..] != finalize(&env.state)[..] {
+            return Err(io::Error::other(Error::ChecksumMismatch));
+        }
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> D {
+        self.inner.into_inner()
+    }
+}
+
+impl<D: BufRead> Read for DeltaInput<D> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = {
+            let data = self.fill_buf()?;
+            let n = data.len().min(buf.len());
+            buf[..n].copy_from_slice(&data[..n]);
+            n
+        };
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl<D: BufRead> BufRead for DeltaInput<D> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        let env = match self.envelope {
+            Some(ref mut env) => env,
+            None => return self.inner.fill_buf(),
+        };
+        // keep at least a trailer worth of data buffered, until the end of the input
+        while !env.eof && env.buf.len() - env.pos <= TRAILER_LEN {
+            env.buf.drain(..env.pos);
+            env.pos = 0;
+            let data = self.inner.fill_buf()?;
+            if data.is_empty() {
+                env.eof = true;
+            }
+            env.buf.extend_from_slice(data);
+            let n = data.len();
+            self.inner.consume(n);
+        }
+        let end = env.buf.len().saturating_sub(TRAILER_LEN).max(env.pos);
+        Ok(&env.buf[env.pos..end])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        match self.envelope {
+            Some(ref mut env) => env.pos += amt,
+            None => self.inner.consume(amt),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn enveloped(delta: &[u8], new: &[u8]) -> Vec<u8> {
+        let mut out = ENVELOPE_MAGIC.to_be_bytes().to_vec();
+        out.extend_from_slice(&[7; HASH_LEN]);
+        out.extend_from_slice(delta);
+        out.extend_from_slice(&(new.len() as u64).to_be_bytes());
+        let mut state = hasher();
+        state.update(new);
+        out.extend_from_slice(&finalize(&state));
+        out
+    }
+
+    // reads the whole input in small steps, as a patch job would do
+    fn read_body<D: BufRead>(input: &mut DeltaInput<D>) -> Vec<u8> {
+        let mut body = Vec::new();
+        let mut buf = [0; 3];
+        loop {
+            let n = input.read(&mut buf).unwrap();
+            if n == 0 {
+                return body;
+            }
+            body.extend_from_slice(&buf[..n]);
+        }
+    }
+
+    // a stream failing if its buffer is requested again before being consumed
+    struct Strict<'a> {
+        data: &'a [u8],
+        filled: bool,
+    }
+
+    impl Read for Strict<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = {
+                let data = self.fill_buf()?;
+                let n = data.len().min(buf.len());
+                buf[..n].copy_from_slice(&data[..n]);
+                n
+            };
+            self.consume(n);
+            Ok(n)
+        }
+    }
+
+    impl BufRead for Strict<'_> {
+        fn fill_buf(&mut self) -> io::Result<&[u8]> {
+            if self.filled {
+                return Err(io::Error::other("buffer requested twice"));
+            }
+            self.filled = true;
+            Ok(&self.data[..self.data.len().min(4)])
+        }
+
+        fn consume(&mut self, amt: usize) {
+            self.data = &self.data[amt..];
+            self.filled = false;
+        }
+    }
+
+    #[test]
+    fn hash_read() {
+        let data = b"some data to be hashed";
+        let mut input = HashRead::new(
+            Strict {
+                data,
+                filled: false,
+            },
+            true,
+        );
+        let mut buf = [0; 3];
+        let mut read = Vec::new();
+        loop {
+            let n = input.read(&mut buf).unwrap();
+            if n == 0 {
+                break;
+            }
+            read.extend_from_slice(&buf[..n]);
+        }
+        assert_eq!(read, data);
+        assert_eq!(input.len, data.len() as u64);
+        let mut state = hasher();
+        state.update(data);
+        assert_eq!(input.hash(), Some(finalize(&state)));
+    }
+
+    #[test]
+    fn plain_input() {
+        let delta = b"rs\x026 plain delta".to_vec();
+        let mut input = DeltaInput::new(&delta[..]).unwrap();
+        assert_eq!(input.fingerprint(), None);
+        assert_eq!(read_body(&mut input), delta);
+        input.check_output(b"anything", true).unwrap();
+
+        let mut input = DeltaInput::new(&b"rs"[..]).unwrap();
+        assert_eq!(read_body(&mut input), b"rs");
+    }
+
+    #[test]
+    fn envelope_input() {
+        let data = enveloped(b"the delta body", b"new file");
+        let mut input = DeltaInput::new(io::BufReader::with_capacity(5, &data[..])).unwrap();
+        assert_eq!(input.fingerprint(), Some([7; HASH_LEN]));
+        assert_eq!(read_body(&mut input), b"the delta body");
+        input.check_output(b"new ", false).unwrap();
+        input.check_output(b"file", true).unwrap();
+    }
+
+    #[test]
+    fn envelope_mismatch() {
+        let data = enveloped(b"a longer delta body!", b"new file");
+        let mut input = DeltaInput::new(&data[..]).unwrap();
+        read_body(&mut input);
+        let err = input.check_output(b"old file", true).unwrap_err();
+        assert!(matches!(Error::from(err), Error::ChecksumMismatch));
+
+        let mut input = DeltaInput::new(&data[..]).unwrap();
+        read_body(&mut input);
+        let err = input.check_output(b"new file!", true).unwrap_err();
+        assert!(matches!(Error::from(err), Error::ChecksumMismatch));
+
+        let mut input = DeltaInput::new(&data[..data.len() - 50]).unwrap();
+        read_body(&mut input);
+        let err = input.check_output(b"", true).unwrap_err();
+        assert!(matches!(Error::from(err), Error::Truncated));
+    }
+}
@@ -0,0 +1,408 @@
+//! Estimation of delta sizes, without running a delta job.
+//!
+//! The weak checksums of a signature are collected while it is loaded. The new file is then
+//! scanned with the same rolling checksum used by librsync, and a block is considered found as
+//! soon as its weak checksum matches. Strong checksums are never computed and no command is
+//! encoded, so the estimation is much cheaper than producing the delta. A false match of a weak
+//! checksum is counted as copied data, which makes the estimation slightly optimistic in rare
+//! cases.
+
+use std::io::{self, BufRead, Read};
+
+use crate::command::{copy_cmd_len, literal_cmd_len};
+use crate::{DeltaEstimate, Result, SignatureHeader};
+
+// Offset added to each byte by the rolling checksum of librsync.
+const ROLLSUM_CHAR_OFFSET: u16 = 31;
+
+// The weak checksums of the blocks of a signature.
+#[derive(Debug, Default)]
+pub struct WeakSums {
+    block_len: usize,
+    sums: Vec<u32>,
+}
+
+// A `BufRead` wrapper over a signature stream, collecting the weak checksums of the blocks as
+// they are consumed.
+pub struct SumTap<I> {
+    inner: I,
+    parser: SumParser,
+}
+
+// The state of the parsing of a signature stream.
+#[derive(Default)]
+struct SumParser {
+    header: Vec<u8>,
+    block_sig_len: Option<usize>,
+    // position in the current block signature, and its weak checksum so far
+    pos: usize,
+    weak_sum: u32,
+    sums: WeakSums,
+}
+
+impl<I: BufRead> SumTap<I> {
+    pub fn new(inner: I) -> Self {
+        SumTap {
+            inner,
+            parser: SumParser::default(),
+        }
+    }
+
+    // Returns the wrapped stream and the weak checksums collected.
+    pub fn into_parts(self) -> (I, WeakSums) {
+        (self.inner, self.parser.sums)
+    }
+}
+
+impl SumParser {
+    fn feed(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let block_sig_len = match self.block_sig_len {
+                Some(len) => len,
+                None if self.header.len() == SignatureHeader::LEN => return,
+                None => {
+                    let n = data.len().min(SignatureHeader::LEN - self.header.len());
+                    self.header.extend_from_slice(&data[..n]);
+                    data = &data[n..];
+                    if self.header.len() == SignatureHeader::LEN {
+                        // an invalid header is reported by the signature loader
+                        if let Ok(header) = SignatureHeader::parse(&mut &self.header[..]) {
+                            self.sums.block_len = header.block_len;
+                            self.block_sig_len = Some(header.block_sig_len());
+                        }
+                    }
+                    continue;
+                }
+            };
+            let n = data.len().min(block_sig_len - self.pos);
+            for &b in data[..n].iter().take(4usize.saturating_sub(self.pos)) {
+                self.weak_sum = self.weak_sum << 8 | u32::from(b);
+            }
+            self.pos += n;
+            data = &data[n..];
+            if self.pos == block_sig_len {
+                self.sums.sums.push(self.weak_sum);
+                self.pos = 0;
+                self.weak_sum = 0;
+            }
+        }
+    }
+}
+
+impl<I: BufRead> Read for SumTap<I> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = {
+            let data = self.fill_buf()?;
+            let n = data.len().min(buf.len());
+            buf[..n].copy_from_slice(&data[..n]);
+            n
+        };
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl<I: BufRead> BufRead for SumTap<I> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        // the data has already been filled, so it is buffered
+        if let Ok(data) = self.inner.fill_buf() {
+            self.parser.feed(&data[..amt]);
+        }
+        self.inner.consume(amt);
+    }
+}
+
+// The rolling checksum of librsync, over a window of `count` bytes.
+#[derive(Clone, Copy, Debug, Default)]
+struct Rollsum {
+    count: usize,
+    s1: u16,
+    s2: u16,
+}
+
+impl Rollsum {
+    fn update(&mut self, data: &[u8]) {
+        for &b in data {
+            self.s1 = self.s1.wrapping_add(u16::from(b) + ROLLSUM_CHAR_OFFSET);
+            self.s2 = self.s2.wrapping_add(self.s1);
+        }
+        self.count += data.len();
+    }
+
+    // Slides the window by one byte.
+    fn rotate(&mut self, out: u8, input: u8) {
+        let out = u16::from(out) + ROLLSUM_CHAR_OFFSET;
+        self.s1 = self
+            .s1
+            .wrapping_add(u16::from(input) + ROLLSUM_CHAR_OFFSET)
+            .wrapping_sub(out);
+        self.s2 = self
+            .s2
+            .wrapping_add(self.s1)
+            .wrapping_sub((self.count as u16).wrapping_mul(out));
+    }
+
+    // Removes the first byte of the window.
+    fn rollout(&mut self, out: u8) {
+        let out = u16::from(out) + ROLLSUM_CHAR_OFFSET;
+        self.s2 = self.s2.wrapping_sub((self.count as u16).wrapping_mul(out));
+        self.s1 = self.s1.wrapping_sub(out);
+        self.count -= 1;
+    }
+
+    fn digest(&self) -> u32 {
+        u32::from(self.s2) << 16 | u32::from(self.s1)
+    }
+}
+
+// An open addressing hash table from the weak checksums to their block indexes.
+struct SumTable<'a> {
+    sums: &'a [u32],
+    // the block index plus one of each slot, or zero for empty slots
+    slots: Vec<usize>,
+    shift: u32,
+}
+
+impl<'a> SumTable<'a> {
+    fn new(sums: &'a [u32]) -> Self {
+        let len = (sums.len() * 2).next_power_of_two().max(2);
+        let mut table = SumTable {
+            sums,
+            slots: vec![0; len],
+            shift: 32u32.saturating_sub(len.trailing_zeros()),
+        };
+        for (index, &sum) in sums.iter().enumerate() {
+            let mut slot = table.slot(sum);
+            while table.slots[slot] != 0 {
+                slot = (slot + 1) % len;
+            }
+            table.slots[slot] = index + 1;
+        }
+        table
+    }
+
+    fn slot(&self, sum: u32) -> usize {
+        (sum.wrapping_mul(0x9e37_79b1) >> self.shift) as usize
+    }
+
+    fn find(&self, sum: u32) -> Option<usize> {
+        let mut slot = self.slot(sum);
+        loop {
+            match self.slots[slot] {
+                0 => return None,
+                index if self.sums[index - 1] == sum => return Some(index - 1),
+                _ => slot = (slot + 1) % self.slots.len(),
+            }
+        }
+    }
+}
+
+// The delta being estimated, with its last command not accounted yet.
+#[derive(Default)]
+struct Estimator {
+    estimate: DeltaEstimate,
+    literal: u64,
+    copy: Option<(u64, u64)>,
+}
+
+impl Estimator {
+    fn literal(&mut self, len: u64) {
+        if self.copy.is_some() {
+            self.flush();
+        }
+        self.literal += len;
+        self.estimate.literal_bytes += len;
+    }
+
+    fn copy(&mut self, offset: u64, len: u64) {
+        if len == 0 {
+            return;
+        }
+        match self.copy {
+            Some((copy_offset, ref mut copy_len))
+                if copy_offset.checked_add(*copy_len) == Some(offset) =>
+            {
+                *copy_len += len;
+            }
+            _ => {
+                self.flush();
+                self.copy = Some((offset, len));
+            }
+        }
+        self.estimate.copy_bytes += len;
+    }
+
+    fn flush(&mut self) {
+        if self.literal > 0 {
+            self.estimate.delta_len += literal_cmd_len(self.literal) + self.literal;
+            self.literal = 0;
+        }
+        if let Some((offset, len)) = self.copy.take() {
+            self.estimate.delta_len += copy_cmd_len(offset, len);
+        }
+    }
+
+    fn finish(mut self) -> DeltaEstimate {
+        self.flush();
+        // magic number and end command
+        self.estimate.delta_len += 4 + 1;
+        self.estimate.new_len = self.estimate.literal_bytes + self.estimate.copy_bytes;
+        self.estimate
+    }
+}
+
+// Estimates the delta of the given new file, after a prefix of `prefix` bytes matching the start
+// of the base file.
+pub fn estimate<I: BufRead>(mut input: I, sums: &WeakSums, prefix: u64) -> Result<DeltaEstimate> {
+    let mut estimator = Estimator::default();
+    estimator.copy(0, prefix);
+    let block_len = sums.block_len;
+    if block_len == 0 || sums.sums.is_empty() {
+        let len = io::copy(&mut input, &mut io::sink())?;
+        estimator.literal(len);
+        return Ok(estimator.finish());
+    }
+    let table = SumTable::new(&sums.sums);
+    let last_index = sums.sums.len() - 1;
+
+    let mut data = Vec::new();
+    // start of the window in `data`, and checksum of the window
+    let mut start = 0;
+    let mut sum = Rollsum::default();
+    // the block following the last one found, which keeps copies contiguous if it matches
+    let mut next_index = None;
+    let mut eof = false;
+    loop {
+        // keep at least a block after the start of the window, and the byte following it
+        if !eof && data.len() - start <= block_len {
+            if start >= data.len() / 2 {
+                data.drain(..start);
+                start = 0;
+            }
+            let buf = input.fill_buf()?;
+            let n = buf.len();
+            data.extend_from_slice(buf);
+            input.consume(n);
+            eof = n == 0;
+            continue;
+        }
+        let avail = data.len() - start;
+        if avail == 0 {
+            break;
+        }
+        if sum.count == 0 {
+            sum.update(&data[start..start + avail.min(block_len)]);
+        }
+        let digest = sum.digest();
+        let found = if sum.count < block_len {
+            // only the last block can be shorter than the others
+            Some(last_index).filter(|&index| sums.sums[index] == digest)
+        } else {
+            next_index
+                .filter(|&index| sums.sums.get(index) == Some(&digest))
+                .or_else(|| table.find(digest))
+        };
+        if let Some(index) = found {
+            estimator.copy(index as u64 * block_len as u64, sum.count as u64);
+            start += sum.count;
+            sum = Rollsum::default();
+            next_index = Some(index + 1);
+            continue;
+        }
+        estimator.literal(1);
+        let out = data[start];
+        start += 1;
+        match data.get(start + sum.count - 1) {
+            Some(&input) => sum.rotate(out, input),
+            None => sum.rollout(out),
+        }
+    }
+    Ok(estimator.finish())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::{DELTA, SIG};
+    use std::io::Cursor;
+
+    fn weak_sums(sig: &[u8]) -> WeakSums {
+        let mut tap = SumTap::new(Cursor::new(sig));
+        io::copy(&mut tap, &mut io::sink()).unwrap();
+        tap.into_parts().1
+    }
+
+    #[test]
+    fn tap() {
+        let sums = weak_sums(&SIG);
+        assert_eq!(sums.block_len, 10);
+        assert_eq!(sums.sums, vec![0x1b21_048b, 0x1d1b_04f0, 0x15f4_0487]);
+
+        // blocks split across reads
+        let mut tap = SumTap::new(io::BufReader::with_capacity(3, Cursor::new(SIG)));
+        io::copy(&mut tap, &mut io::sink()).unwrap();
+        assert_eq!(tap.into_parts().1.sums, sums.sums);
+    }
+
+    #[test]
+    fn rollsum() {
+        let data = b"this is a string to be tested";
+        let mut sum = Rollsum::default();
+        sum.update(&data[..10]);
+        assert_eq!(sum.digest(), 0x1b21_048b);
+        for i in 0..10 {
+            sum.rotate(data[i], data[i + 10]);
+        }
+        assert_eq!(sum.digest(), 0x1d1b_04f0);
+
+        let mut sum = Rollsum::default();
+        sum.update(&data[19..]);
+        sum.rollout(data[19]);
+        assert_eq!(sum.digest(), 0x15f4_0487);
+    }
+
+    #[test]
+    fn estimate_delta() {
+        let sums = weak_sums(&SIG);
+        let new = b"this is another string to be tested";
+        let est = estimate(Cursor::new(new), &sums, 0).unwrap();
+        assert_eq!(
+            est,
+            DeltaEstimate {
+                new_len: new.len() as u64,
+                delta_len: DELTA.len() as u64,
+                literal_bytes: 16,
+                copy_bytes: 19,
+            }
+        );
+
+        // the full blocks of the base file repeated twice, read through a small buffer
+        let new = b"this is a string to this is a string to ";
+        let input = io::BufReader::with_capacity(4, Cursor::new(new));
+        let est = estimate(input, &sums, 0).unwrap();
+        assert_eq!(
+            est,
+            DeltaEstimate {
+                new_len: 40,
+                delta_len: 4 + 3 + 3 + 1,
+                literal_bytes: 0,
+                copy_bytes: 40,
+            }
+        );
+
+        let est = estimate(Cursor::new(b"unrelated"), &WeakSums::default(), 7).unwrap();
+        assert_eq!(
+            est,
+            DeltaEstimate {
+                new_len: 16,
+                delta_len: 4 + 3 + 1 + 9 + 1,
+                literal_bytes: 9,
+                copy_bytes: 7,
+            }
+        );
+    }
+}
@@ -0,0 +1,211 @@
+//! Fast path of deltas for files that are unchanged or only grew.
+//!
+//! While a signature is loaded, its blocks are compared with the leading blocks of the new file,
+//! at their original positions. The matching prefix is then sent as a single copy command, and
+//! only the rest of the new file goes through the delta job.
+
+use std::collections::VecDeque;
+use std::io::{self, BufRead, Read};
+
+use crate::command::DeltaWriter;
+use crate::compress::Prefixed;
+use crate::envelope::HashRead;
+use crate::job::JobDriver;
+use crate::{
+    Result, Signature, SignatureBlocks, SignatureHeader, SignatureOptions, Stats, read_full,
+};
+
+// Approximate amount of the new file whose block signatures are computed at once.
+const BATCH_LEN: usize = 1 << 16;
+
+// A `Read` wrapper over a signature stream, matching its blocks against the leading blocks of
+// the new file as they are read.
+pub struct PrefixMatch<'a, S, N: ?Sized> {
+    sig: S,
+    new: &'a mut N,
+    // the signature data not parsed yet
+    pending: Vec<u8>,
+    header: Option<SignatureHeader>,
+    matching: bool,
+    // the data of the new file read but not matched yet, and the checksums of its blocks
+    batch: Vec<u8>,
+    batch_pos: usize,
+    sums: VecDeque<(u32, Vec<u8>, usize)>,
+    matched: u64,
+}
+
+impl<'a, S: Read, N: Read + ?Sized> PrefixMatch<'a, S, N> {
+    pub fn new(sig: S, new: &'a mut N) -> Self {
+        PrefixMatch {
+            sig,
+            new,
+            pending: Vec::new(),
+            header: None,
+            matching: true,
+            batch: Vec::new(),
+            batch_pos: 0,
+            sums: VecDeque::new(),
+            matched: 0,
+        }
+    }
+
+    // Returns the length of the matching prefix, and the data of the new file read after it.
+    pub fn finish(mut self) -> (u64, Vec<u8>) {
+        let rest = self.batch.split_off(self.batch_pos);
+        (self.matched, rest)
+    }
+
+    fn feed(&mut self, data: &[u8]) -> Result<()> {
+        if !self.matching {
+            return Ok(());
+        }
+        self.pending.extend_from_slice(data);
+        let mut pos = 0;
+        while self.matching {
+            let header = match self.header {
+                Some(header) => header,
+                None if self.pending.len() < SignatureHeader::LEN => break,
+                None => {
+                    pos = SignatureHeader::LEN;
+                    match SignatureHeader::parse(&mut &self.pending[..pos]) {
+                        Ok(header) => *self.header.insert(header),
+                        // not a valid signature, the delta job reports the error
+                        Err(_) => {
+                            self.matching = false;
+                            break;
+                        }
+                    }
+                }
+            };
+            let end = pos + header.block_sig_len();
+            if end > self.pending.len() {
+                break;
+            }
+            let block = &self.pending[pos..end];
+            let weak_sum = u32::from_be_bytes([block[0], block[1], block[2], block[3]]);
+            let strong_sum = block[4..].to_vec();
+            self.matching = self.match_block(header, weak_sum, strong_sum)?;
+            pos = end;
+        }
+        if self.matching {
+            self.pending.drain(..pos);
+        } else {
+            self.pending = Vec::new();
+        }
+        Ok(())
+    }
+
+    // Matches a block of the signature with the next block of the new file.
+    fn match_block(
+        &mut self,
+        header: SignatureHeader,
+        weak_sum: u32,
+        strong_sum: Vec<u8>,
+    ) -> Result<bool> {
+        if self.sums.is_empty() {
+            self.next_batch(header)?;
+        }
+        match self.sums.pop_front() {
+            Some((weak, strong, len)) if weak == weak_sum && strong == strong_sum => {
+                self.matched += len as u64;
+                self.batch_pos += len;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    // Reads the next batch of blocks of the new file, and computes their checksums.
+    fn next_batch(&mut self, header: SignatureHeader) -> Result<()> {
+        let block_len = header.block_len;
+        self.batch
+            .resize((BATCH_LEN / block_len).max(1) * block_len, 0);
+        let n = read_full(&mut *self.new, &mut self.batch)?;
+        self.batch.truncate(n);
+        self.batch_pos = 0;
+
+        let opts = SignatureOptions::builder(header.sig_type)
+            .block_len(block_len)
+            .strong_len(header.strong_len)
+            .build()?;
+        let mut sig = Vec::new();
+        Signature::with_options(&self.batch[..], opts)?.read_to_end(&mut sig)?;
+        for (i, block) in SignatureBlocks::new(&sig[..])?.enumerate() {
+            let block = block?;
+            let len = block_len.min(n - i * block_len);
+            self.sums.push_back((block.weak_sum, block.strong_sum, len));
+        }
+        Ok(())
+    }
+}
+
+impl<S: Read, N: Read + ?Sized> Read for PrefixMatch<'_, S, N> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.sig.read(buf)?;
+        self.feed(&buf[..n]).map_err(io::Error::other)?;
+        Ok(n)
+    }
+}
+
+// The copy command for the matching prefix, written before the output of the delta job.
+pub struct CopyPrefix {
+    header: Vec<u8>,
+    pos: usize,
+    // the number of bytes of the magic number of the job output still to be dropped
+    skip: usize,
+    len: u64,
+}
+
+impl CopyPrefix {
+    pub fn new(len: u64) -> Result<Self> {
+        let mut writer = DeltaWriter::new(Vec::new())?;
+        writer.copy(0, len)?;
+        writer.finish()?;
+        let mut header = writer.into_inner();
+        // drop the end command, written by the delta job
+        header.pop();
+        Ok(CopyPrefix {
+            header,
+            pos: 0,
+            skip: 4,
+            len,
+        })
+    }
+
+    // Returns the length of the matching prefix.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    // Reads the output of the given delta job, prefixed by the copy command.
+    pub fn read<R: BufRead>(
+        &mut self,
+        driver: &mut JobDriver<HashRead<Prefixed<R>>>,
+        buf: &mut [u8],
+    ) -> io::Result<usize> {
+        if self.pos < self.header.len() {
+            let n = buf.len().min(self.header.len() - self.pos);
+            buf[..n].copy_from_slice(&self.header[self.pos..self.pos + n]);
+            self.pos += n;
+            return Ok(n);
+        }
+        while self.skip > 0 {
+            let mut magic = [0; 4];
+            let n = driver.read(&mut magic[..self.skip])?;
+            if n == 0 {
+                return Ok(0);
+            }
+            self.skip -= n;
+        }
+        driver.read(buf)
+    }
+
+    // Accounts for the copy command in the statistics of the delta job.
+    pub fn update_stats(&self, stats: &mut Stats) {
+        stats.copy_cmds += 1;
+        stats.copy_bytes += self.len;
+        stats.copy_cmdbytes += (self.header.len() - 4) as u64;
+        stats.in_bytes += self.len;
+        stats.out_bytes += (self.header.len() - 4) as u64;
+    }
+}
@@ -3,7 +3,7 @@ use std::marker::PhantomData;
 use std::ops::Deref;
 use std::ptr;
 
-use crate::{Error, raw};
+use crate::{Error, Stats, raw};
 
 pub struct JobDriver<R> {
     input: R,
@@ -32,6 +32,18 @@ impl<R: BufRead> JobDriver<R> {
         self.input
     }
 
+    pub fn get_ref(&self) -> &R {
+        &self.input
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.input
+    }
+
+    pub fn stats(&self) -> Stats {
+        unsafe { Stats::from_raw(&*raw::rs_job_statistics(*self.job)) }
+    }
+
     /// Complete the job by working without an output buffer.
     ///
     /// If the job needs to write some data, an `ErrorKind::WouldBlock` error is returned.
@@ -105,11 +105,39 @@ extern crate librsync_sys as raw;
 #[macro_use]
 extern crate log;
 
+mod command;
+mod compress;
+pub mod dump;
+mod envelope;
+mod estimate;
+mod fastpath;
 mod job;
 mod logfwd;
 mod macros;
+mod netint;
+#[cfg(feature = "serde")]
+mod serde_impl;
+mod sig;
+#[cfg(test)]
+mod test_util;
+mod transform;
+pub mod vcdiff;
 pub mod whole;
 
+pub use crate::command::{DeltaReader, DeltaSummary, DeltaWriter, Op};
+#[cfg(any(feature = "zstd", feature = "gzip"))]
+pub use crate::compress::{Compressed, Compression};
+pub use crate::sig::{
+    SignatureBlock, SignatureBlocks, SignatureDiff, SignatureHeader, signature_diff,
+    truncate_signature,
+};
+pub use crate::transform::{compose_deltas, optimize_delta, slice_delta};
+
+use crate::compress::{Decompressed, Prefixed};
+use crate::envelope::OVERHEAD as ENVELOPE_OVERHEAD;
+use crate::envelope::{DeltaInput, EnvelopeWriter, HASH_LEN, HashRead};
+use crate::estimate::{SumTap, WeakSums};
+use crate::fastpath::{CopyPrefix, PrefixMatch};
 use crate::job::{Job, JobDriver};
 
 use std::cell::{RefCell, RefMut};
@@ -121,9 +149,12 @@ use std::ops::Deref;
 use std::ptr;
 use std::rc::Rc;
 use std::slice;
+use std::sync::Arc;
+use std::time::Duration;
 
 /// The signature type.
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum SignatureType {
     /// A signature file with MD4 signatures.
     ///
@@ -147,6 +178,18 @@ pub enum Error {
     Unimplemented,
     /// Probably a library bug.
     Internal,
+    /// Invalid signature block length.
+    BadBlockLen(usize),
+    /// Invalid strong signature length for the chosen signature type.
+    BadStrongLen(usize),
+    /// The signatures have been generated with different parameters.
+    SignatureMismatch,
+    /// Unexpected end of stream.
+    Truncated,
+    /// Unknown command in a delta stream.
+    BadCommand(u8),
+    /// The patched file does not match the checksum recorded in the delta envelope.
+    ChecksumMismatch,
     /// All the other error numbers.
     ///
     /// This error should never occur, as it is an indication of a bug.
@@ -156,6 +199,87 @@ pub enum Error {
 /// A `Result` type alias for this crate's `Error` type.
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// The parameters used to generate a signature.
+///
+/// Options are built through a `SignatureOptionsBuilder`, which validates them, or by
+/// `SignatureOptions::recommended`. The default options use BLAKE2 for the hashing, 2048 bytes
+/// for the block length and full length for the strong signature size.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(try_from = "crate::serde_impl::SignatureOptionsDef")
+)]
+pub struct SignatureOptions {
+    block_len: usize,
+    strong_len: usize,
+    sig_type: SignatureType,
+}
+
+/// A builder for `SignatureOptions`.
+///
+/// Parameters not explicitly set take their default values: 2048 bytes for the block length and
+/// full length for the strong signature size.
+#[derive(Clone, Copy, Debug)]
+pub struct SignatureOptionsBuilder {
+    block_len: usize,
+    strong_len: Option<usize>,
+    sig_type: SignatureType,
+}
+
+/// Statistics of a signature, delta or patch job.
+///
+/// Not all the statistics are relevant for every kind of job. For example, literal and copy
+/// commands are counted only by delta and patch jobs.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Stats {
+    /// Number of literal commands.
+    pub lit_cmds: u64,
+    /// Number of literal bytes.
+    pub lit_bytes: u64,
+    /// Number of bytes used in literal command headers.
+    pub lit_cmdbytes: u64,
+    /// Number of copy commands.
+    pub copy_cmds: u64,
+    /// Number of bytes copied from the base file.
+    pub copy_bytes: u64,
+    /// Number of bytes used in copy command headers.
+    pub copy_cmdbytes: u64,
+    /// Number of signature commands.
+    pub sig_cmds: u64,
+    /// Number of signature bytes.
+    pub sig_bytes: u64,
+    /// Number of blocks whose weak checksum matched, but not the strong one.
+    pub false_matches: u64,
+    /// Number of blocks described by the signature.
+    pub sig_blocks: u64,
+    /// The signature block length.
+    pub block_len: usize,
+    /// Total bytes read from the input.
+    pub in_bytes: u64,
+    /// Total bytes written to the output.
+    pub out_bytes: u64,
+    /// Time spent by the job, with a resolution of one second.
+    pub elapsed: Duration,
+}
+
+/// The projected size of a delta, computed without producing it.
+///
+/// See `Delta::estimate`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DeltaEstimate {
+    /// The length of the new file.
+    pub new_len: u64,
+    /// The length of the delta.
+    pub delta_len: u64,
+    /// The number of bytes of the new file that would be sent as literal data.
+    pub literal_bytes: u64,
+    /// The number of bytes of the new file found in the base file.
+    pub copy_bytes: u64,
+}
+
 /// A struct to generate a signature.
 ///
 /// This type takes a `Read` stream for the input from which compute the signatures, and implements
@@ -169,8 +293,22 @@ pub struct Signature<R> {
 /// This type takes two `Read` streams, one for the signature of the base file and one for the new
 /// file. It then provides another `Read` stream from which get the result.
 pub struct Delta<R> {
-    driver: JobDriver<R>,
-    _sumset: Sumset,
+    driver: JobDriver<HashRead<Prefixed<R>>>,
+    envelope: Option<EnvelopeWriter>,
+    prefix: Option<CopyPrefix>,
+    sig: Arc<LoadedSignature>,
+}
+
+/// A signature loaded in memory, ready to be used for delta computations.
+///
+/// Loading a signature requires parsing the whole signature stream and building a hash table of
+/// its blocks. This type allows to do that only once, and to share the result among many `Delta`
+/// jobs, possibly running in different threads (by wrapping it into an `Arc`). The weak
+/// checksums of the blocks are kept as well, four bytes per block, for `Delta::estimate`.
+pub struct LoadedSignature {
+    sumset: Sumset,
+    fingerprint: [u8; HASH_LEN],
+    weak_sums: WeakSums,
 }
 
 /// A struct to apply a delta to a basis file, to recreate the new file.
@@ -178,11 +316,16 @@ pub struct Delta<R> {
 /// This type takes a `Read + Seek` stream for the base file, and a `Read` stream for the delta
 /// file. It then provides another `Read` stream from which get the resulting patched file.
 pub struct Patch<'a, B: 'a, D> {
-    driver: JobDriver<D>,
+    driver: JobDriver<DeltaInput<Decompressed<D>>>,
     base: Rc<RefCell<B>>,
     raw: *mut Rc<RefCell<dyn ReadAndSeek + 'a>>,
 }
 
+// Fraction of the recommended block length used by signatures that are never transferred, and
+// the minimum block length of those signatures.
+const LOCAL_BLOCK_LEN_DIVISOR: u64 = 4;
+const MIN_LOCAL_BLOCK_LEN: u64 = 64;
+
 struct Sumset(*mut raw::rs_signature_t);
 
 // workaround for E0225
@@ -196,23 +339,140 @@ impl<R: Read> Signature<BufReader<R>> {
     /// Default options are used for the signature format: BLAKE2 for the hashing, 2048 bytes for
     /// the block length and full length for the strong signature size.
     pub fn new(input: R) -> Result<Self> {
-        Self::with_options(input, raw::RS_DEFAULT_BLOCK_LEN, 0, SignatureType::Blake2)
+        Self::with_options(input, SignatureOptions::default())
+    }
+
+    /// Creates a new signature stream with parameters tuned for the given input size.
+    ///
+    /// This constructor takes an input stream for the file from which compute the signatures, and
+    /// its expected size in bytes. BLAKE2 is used for the hashing, while the block length and the
+    /// strong signature size are chosen by `SignatureOptions::recommended`.
+    pub fn for_len(input: R, file_len: u64) -> Result<Self> {
+        let opts = SignatureOptions::recommended(file_len, SignatureType::Blake2)?;
+        Self::with_options(input, opts)
     }
 
     /// Creates a new signature stream by specifying custom parameters.
     ///
-    /// This constructor takes the input stream for the file from which compute the signatures, the
-    /// size of checksum blocks as `block_len` parameter (larger values make the signature shorter
-    /// and the delta longer), and the size of strong signatures in bytes as `strong_len`
-    /// parameter. If it is non-zero the signature will be truncated to that amount of bytes.
-    /// The last parameter specifies which version of the signature format to be used.
-    pub fn with_options(
-        input: R,
-        block_len: usize,
-        strong_len: usize,
-        sig_magic: SignatureType,
-    ) -> Result<Self> {
-        Self::with_buf_read(BufReader::new(input), block_len, strong_len, sig_magic)
+    /// This constructor takes the input stream for the file from which compute the signatures, and
+    /// the options specifying the signature format, the size of checksum blocks (larger values
+    /// make the signature shorter and the delta longer) and the size of strong signatures.
+    pub fn with_options(input: R, opts: SignatureOptions) -> Result<Self> {
+        Self::with_buf_read(BufReader::new(input), opts)
+    }
+}
+
+impl SignatureOptions {
+    /// Creates a builder for options of the given signature type.
+    pub fn builder(sig_type: SignatureType) -> SignatureOptionsBuilder {
+        SignatureOptionsBuilder {
+            block_len: raw::RS_DEFAULT_BLOCK_LEN,
+            strong_len: None,
+            sig_type,
+        }
+    }
+
+    /// Returns the recommended signature parameters for a file of the given size.
+    ///
+    /// The block length grows with the square root of the file size, to balance the size of the
+    /// signature against the size of the delta. The strong signatures are truncated to the
+    /// minimum length that keeps the probability of a false match negligible for that file size.
+    pub fn recommended(file_len: u64, sig_type: SignatureType) -> Result<Self> {
+        // same choices as `rs_sig_args` in librsync 2.2, which the vendored library predates
+        let file_len = file_len.min(i64::MAX as u64);
+        Ok(Self::sized(
+            file_len,
+            recommended_block_len(file_len),
+            sig_type,
+        ))
+    }
+
+    // Returns the parameters for signatures computed and used in place, and never transferred.
+    //
+    // A fraction of the recommended block length is used, which finds more matches and gives
+    // smaller deltas, at the cost of a larger signature held in memory.
+    fn local(file_len: u64, sig_type: SignatureType) -> Self {
+        let file_len = file_len.min(i64::MAX as u64);
+        let block_len =
+            (recommended_block_len(file_len) / LOCAL_BLOCK_LEN_DIVISOR).max(MIN_LOCAL_BLOCK_LEN);
+        Self::sized(file_len, block_len, sig_type)
+    }
+
+    // Returns the parameters with the given block length, and the minimum safe strong length.
+    fn sized(file_len: u64, block_len: u64, sig_type: SignatureType) -> Self {
+        // log2 of the file size (at least 24) plus log2 of the block count, in bytes, plus 2 bytes
+        let bits = ln2(file_len + (1 << 24)) + ln2(file_len / block_len + 1);
+        let strong_len = (2 + bits.div_ceil(8)) as usize;
+        SignatureOptions {
+            block_len: block_len as usize,
+            strong_len: strong_len.min(sig_type.max_strong_len()),
+            sig_type,
+        }
+    }
+
+    /// Returns the size of the checksum blocks, in bytes.
+    pub fn block_len(&self) -> usize {
+        self.block_len
+    }
+
+    /// Returns the size of the strong signatures, in bytes.
+    pub fn strong_len(&self) -> usize {
+        self.strong_len
+    }
+
+    /// Returns the signature format.
+    pub fn sig_type(&self) -> SignatureType {
+        self.sig_type
+    }
+}
+
+impl Default for SignatureOptions {
+    fn default() -> Self {
+        let sig_type = SignatureType::Blake2;
+        SignatureOptions {
+            block_len: raw::RS_DEFAULT_BLOCK_LEN,
+            strong_len: sig_type.max_strong_len(),
+            sig_type,
+        }
+    }
+}
+
+impl SignatureOptionsBuilder {
+    /// Sets the size of the checksum blocks, in bytes.
+    ///
+    /// Larger values make the signature shorter and the delta longer.
+    pub fn block_len(mut self, block_len: usize) -> Self {
+        self.block_len = block_len;
+        self
+    }
+
+    /// Sets the size of the strong signatures, in bytes.
+    ///
+    /// The strong signatures are truncated to this length, which cannot exceed the size of the
+    /// hash used by the signature type (16 bytes for MD4 and 32 bytes for BLAKE2).
+    pub fn strong_len(mut self, strong_len: usize) -> Self {
+        self.strong_len = Some(strong_len);
+        self
+    }
+
+    /// Validates the parameters and builds the options.
+    ///
+    /// Returns `Error::BadBlockLen` or `Error::BadStrongLen` if the corresponding parameter is
+    /// out of range.
+    pub fn build(self) -> Result<SignatureOptions> {
+        if self.block_len == 0 || self.block_len > u32::MAX as usize {
+            return Err(Error::BadBlockLen(self.block_len));
+        }
+        let max_strong_len = self.sig_type.max_strong_len();
+        let strong_len = self.strong_len.unwrap_or(max_strong_len);
+        if strong_len == 0 || strong_len > max_strong_len {
+            return Err(Error::BadStrongLen(strong_len));
+        }
+        Ok(SignatureOptions {
+            block_len: self.block_len,
+            strong_len,
+            sig_type: self.sig_type,
+        })
     }
 }
 
@@ -223,15 +483,11 @@ impl<R: BufRead> Signature<R> {
     /// you already have a `BufRead` as input stream, since it avoids wrapping the input stream
     /// into another `BufRead` instance. See `with_options` constructor for details on the other
     /// parameters.
-    pub fn with_buf_read(
-        input: R,
-        block_len: usize,
-        strong_len: usize,
-        sig_magic: SignatureType,
-    ) -> Result<Self> {
+    pub fn with_buf_read(input: R, opts: SignatureOptions) -> Result<Self> {
         logfwd::init();
 
-        let job = unsafe { raw::rs_sig_begin(block_len, strong_len, sig_magic.as_raw()) };
+        let job =
+            unsafe { raw::rs_sig_begin(opts.block_len, opts.strong_len, opts.sig_type.as_raw()) };
         if job.is_null() {
             return Err(Error::BadMagic);
         }
@@ -240,6 +496,11 @@ impl<R: BufRead> Signature<R> {
         })
     }
 
+    /// Returns the statistics of the job so far.
+    pub fn stats(&self) -> Stats {
+        self.driver.stats()
+    }
+
     /// Unwraps this stream, returning the underlying input stream.
     pub fn into_inner(self) -> R {
         self.driver.into_inner()
@@ -261,6 +522,68 @@ impl<R: Read> Delta<BufReader<R>> {
     pub fn new<S: Read + ?Sized>(new: R, base_sig: &mut S) -> Result<Self> {
         Self::with_buf_read(BufReader::new(new), base_sig)
     }
+
+    /// Creates a new delta stream from an already loaded signature.
+    ///
+    /// This constructor takes a `Read` stream for the new file (`new` parameter) and the loaded
+    /// signature of the base file (`base_sig` parameter). The same signature can be shared among
+    /// multiple delta jobs, avoiding to load it every time.
+    pub fn with_signature(new: R, base_sig: Arc<LoadedSignature>) -> Result<Self> {
+        Self::with_buf_read_signature(BufReader::new(new), base_sig)
+    }
+
+    /// Creates a new delta stream, wrapped in an integrity-checked envelope.
+    ///
+    /// This constructor behaves like `with_signature`, but the delta is wrapped in an envelope
+    /// recording the fingerprint of the signature, the length and the BLAKE2 hash of the new file.
+    /// `Patch` recognizes the envelope, and fails with `Error::ChecksumMismatch` if the patched
+    /// file does not match the recorded hash, for example because the wrong base file was used.
+    pub fn with_envelope(new: R, base_sig: Arc<LoadedSignature>) -> Result<Self> {
+        Self::with_buf_read_envelope(BufReader::new(new), base_sig)
+    }
+
+    /// Creates a new delta stream, with a fast path for files that are unchanged or only grew.
+    ///
+    /// This constructor behaves like `new`, but while the signature is loaded its blocks are
+    /// compared with the leading blocks of the new file, at their original positions. The
+    /// matching prefix of the new file is sent as a single copy command, without rolling
+    /// checksums over it, and only the rest of the file is matched against the signature as
+    /// usual. An unchanged file gives a delta made of a single copy command, and a file that only
+    /// grew gives a copy command followed by the literal tail. Signatures compressed by
+    /// `Compressed` are supported, and any signature that cannot be inspected falls back to a
+    /// regular delta.
+    pub fn with_fast_path<S: Read + ?Sized>(new: R, base_sig: &mut S) -> Result<Self> {
+        Self::with_buf_read_fast_path(BufReader::new(new), base_sig)
+    }
+
+    /// Creates a new delta stream between two files, without a separate signature step.
+    ///
+    /// This constructor takes a `Read + Seek` stream for the base file (`base` parameter) and a
+    /// `Read` stream for the new file (`new` parameter). The signature of the base file is
+    /// computed and loaded in memory, without being serialized. If no options are given, BLAKE2
+    /// signatures are used, with a quarter of the block length recommended for the length of the
+    /// base file (see `SignatureOptions::recommended`), and at least 64 bytes. Since the signature
+    /// is never transferred, smaller blocks find more matches, at the cost of more memory. The
+    /// base stream is only seeked to get its length, and is then read from its current position.
+    pub fn from_files<B: Read + Seek>(
+        mut base: B,
+        new: R,
+        opts: Option<SignatureOptions>,
+    ) -> Result<Self> {
+        let opts = match opts {
+            Some(opts) => opts,
+            None => {
+                let pos = base.stream_position()?;
+                let end = base.seek(io::SeekFrom::End(0))?;
+                base.seek(io::SeekFrom::Start(pos))?;
+                SignatureOptions::local(end.saturating_sub(pos), SignatureType::Blake2)
+            }
+        };
+        let mut sig = Signature::with_options(base, opts)?;
+        // the signature is never serialized, so it does not need a fingerprint
+        let sig = LoadedSignature::load(&mut sig, false)?;
+        Self::with_signature(new, Arc::new(sig))
+    }
 }
 
 impl<R: BufRead> Delta<R> {
@@ -271,23 +594,61 @@ impl<R: BufRead> Delta<R> {
     /// since it avoids wrapping the input stream into another `BufRead` instance. See `new`
     /// constructor for more details on the parameters.
     pub fn with_buf_read<S: Read + ?Sized>(new: R, base_sig: &mut S) -> Result<Self> {
+        // the signature is only used by this job, which is not enveloped
+        let sig = LoadedSignature::load(base_sig, false)?;
+        Self::with_buf_read_signature(new, Arc::new(sig))
+    }
+
+    /// Creates a new delta stream by using a `BufRead` as new file and an already loaded
+    /// signature.
+    ///
+    /// This constructor specializes the `with_signature` constructor by taking a `BufRead`
+    /// instance as `new` parameter. See `with_signature` constructor for more details on the
+    /// parameters.
+    pub fn with_buf_read_signature(new: R, base_sig: Arc<LoadedSignature>) -> Result<Self> {
+        Self::with_job(new, base_sig, false)
+    }
+
+    /// Creates a new delta stream, wrapped in an integrity-checked envelope, by using a `BufRead`
+    /// as new file.
+    ///
+    /// This constructor specializes the `with_envelope` constructor by taking a `BufRead`
+    /// instance as `new` parameter. See `with_envelope` constructor for more details on the
+    /// parameters.
+    pub fn with_buf_read_envelope(new: R, base_sig: Arc<LoadedSignature>) -> Result<Self> {
+        Self::with_job(new, base_sig, true)
+    }
+
+    /// Creates a new delta stream, with a fast path for files that are unchanged or only grew,
+    /// by using a `BufRead` as new file.
+    ///
+    /// This constructor specializes the `with_fast_path` constructor by taking a `BufRead`
+    /// instance as `new` parameter. See `with_fast_path` constructor for more details on the
+    /// parameters.
+    pub fn with_buf_read_fast_path<S: Read + ?Sized>(mut new: R, base_sig: &mut S) -> Result<Self> {
+        let mut sig = PrefixMatch::new(Decompressed::new(BufReader::new(base_sig))?, &mut new);
+        let loaded = LoadedSignature::load_decompressed(BufReader::new(&mut sig), false)?;
+        let (len, rest) = sig.finish();
+        let mut delta =
+            Self::with_input(Prefixed::with_prefix(new, rest), Arc::new(loaded), false)?;
+        if len > 0 {
+            delta.prefix = Some(CopyPrefix::new(len)?);
+        }
+        Ok(delta)
+    }
+
+    fn with_job(new: R, base_sig: Arc<LoadedSignature>, envelope: bool) -> Result<Self> {
+        Self::with_input(Prefixed::with_prefix(new, Vec::new()), base_sig, envelope)
+    }
+
+    fn with_input(
+        new: Prefixed<R>,
+        base_sig: Arc<LoadedSignature>,
+        envelope: bool,
+    ) -> Result<Self> {
         logfwd::init();
 
-        // load the signature
-        let sumset = unsafe {
-            let mut sumset = ptr::null_mut();
-            let job = raw::rs_loadsig_begin(&mut sumset);
-            assert!(!job.is_null());
-            let mut job = JobDriver::new(BufReader::new(base_sig), Job(job));
-            job.consume_input()?;
-            let sumset = Sumset(sumset);
-            let res = raw::rs_build_hash_table(*sumset);
-            if res != raw::RS_DONE {
-                return Err(Error::from(res));
-            }
-            sumset
-        };
-        let job = unsafe { raw::rs_delta_begin(*sumset) };
+        let job = unsafe { raw::rs_delta_begin(*base_sig.sumset) };
         if job.is_null() {
             return Err(io_err(
                 io::ErrorKind::InvalidData,
@@ -295,20 +656,115 @@ impl<R: BufRead> Delta<R> {
             ));
         }
         Ok(Delta {
-            driver: JobDriver::new(new, Job(job)),
-            _sumset: sumset,
+            driver: JobDriver::new(HashRead::new(new, envelope), Job(job)),
+            envelope: if envelope {
+                Some(EnvelopeWriter::new(&base_sig.fingerprint))
+            } else {
+                None
+            },
+            prefix: None,
+            sig: base_sig,
         })
     }
 
+    /// Returns the statistics of the job so far.
+    pub fn stats(&self) -> Stats {
+        let mut stats = self.driver.stats();
+        if let Some(ref prefix) = self.prefix {
+            prefix.update_stats(&mut stats);
+        }
+        stats
+    }
+
+    /// Matches the new file against the signature without producing the delta, and returns the
+    /// projected size of the delta.
+    ///
+    /// This consumes the rest of the new file, which is scanned with the rolling checksum of
+    /// librsync and looked up in the weak checksums of the signature. Strong checksums are not
+    /// computed, and no delta command is encoded, so this costs much less CPU and memory than
+    /// computing the delta. In the rare case of a false match of a weak checksum, the estimation
+    /// counts as copied some data that the delta would send as literal. It is useful to decide
+    /// whether sending a delta is worthwhile compared to sending the whole new file.
+    ///
+    /// The estimation should be done before reading from this stream, since the data of the new
+    /// file already used by the delta job is not taken into account.
+    pub fn estimate(self) -> Result<DeltaEstimate> {
+        let prefix = self.prefix.as_ref().map_or(0, CopyPrefix::len);
+        let mut input = self.driver.into_inner();
+        input.stop_hashing();
+        let mut estimate = estimate::estimate(input, &self.sig.weak_sums, prefix)?;
+        if self.envelope.is_some() {
+            estimate.delta_len += ENVELOPE_OVERHEAD;
+        }
+        Ok(estimate)
+    }
+
     /// Unwraps this stream, returning the underlying new file stream.
     pub fn into_inner(self) -> R {
-        self.driver.into_inner()
+        self.driver.into_inner().into_inner().into_inner()
     }
 }
 
 impl<R: BufRead> Read for Delta<R> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        self.driver.read(buf)
+        if let Some(ref mut prefix) = self.prefix {
+            return prefix.read(&mut self.driver, buf);
+        }
+        match self.envelope {
+            Some(ref mut envelope) => envelope.read(&mut self.driver, buf),
+            None => self.driver.read(buf),
+        }
+    }
+}
+
+impl LoadedSignature {
+    /// Loads a signature from a stream.
+    ///
+    /// This constructor consumes the given signature stream, as produced by `Signature`, and
+    /// builds the hash table needed to compute deltas against it. Signatures compressed by
+    /// `Compressed` are detected and decompressed automatically.
+    pub fn new<S: Read + ?Sized>(sig: &mut S) -> Result<Self> {
+        Self::load(sig, true)
+    }
+
+    // Loads a signature, by computing its fingerprint only if requested. Otherwise the
+    // fingerprint is left zeroed, so the signature must not be used for enveloped deltas.
+    fn load<S: Read + ?Sized>(sig: &mut S, fingerprint: bool) -> Result<Self> {
+        Self::load_decompressed(Decompressed::new(BufReader::new(sig))?, fingerprint)
+    }
+
+    fn load_decompressed<I: BufRead>(sig: I, fingerprint: bool) -> Result<Self> {
+        logfwd::init();
+
+        unsafe {
+            let mut sumset = ptr::null_mut();
+            let job = raw::rs_loadsig_begin(&mut sumset);
+            assert!(!job.is_null());
+            let input = SumTap::new(HashRead::new(sig, fingerprint));
+            let mut job = JobDriver::new(input, Job(job));
+            job.consume_input()?;
+            let sumset = Sumset(sumset);
+            let res = raw::rs_build_hash_table(*sumset);
+            if res != raw::RS_DONE {
+                return Err(Error::from(res));
+            }
+            let (input, weak_sums) = job.into_inner().into_parts();
+            let fingerprint = input.hash().unwrap_or([0; HASH_LEN]);
+            Ok(LoadedSignature {
+                sumset,
+                fingerprint,
+                weak_sums,
+            })
+        }
+    }
+
+    /// Returns the fingerprint of the signature.
+    ///
+    /// The fingerprint is the BLAKE2 hash of the whole signature stream. It is recorded in delta
+    /// envelopes, and can be compared with `Patch::signature_fingerprint` to make sure that a
+    /// delta is applied to the right base file.
+    pub fn fingerprint(&self) -> [u8; 32] {
+        self.fingerprint
     }
 }
 
@@ -318,6 +774,11 @@ impl<'a, B: Read + Seek + 'a, D: Read> Patch<'a, B, BufReader<D>> {
     /// This constructor takes a `Read + Seek` stream for the basis file (`base` parameter), and a
     /// `Read` stream for the delta file (`delta` parameter). It produces a stream from which read
     /// the resulting patched file.
+    ///
+    /// Deltas wrapped in an envelope (see `Delta::with_envelope`) are detected automatically. In
+    /// that case, once the patched file is complete, its length and hash are checked against the
+    /// ones recorded in the envelope, and a mismatch is reported as `Error::ChecksumMismatch`.
+    /// Deltas compressed by `Compressed` are detected and decompressed automatically as well.
     pub fn new(base: B, delta: D) -> Result<Self> {
         Self::with_buf_read(base, BufReader::new(delta))
     }
@@ -335,6 +796,7 @@ impl<'a, B: Read + Seek + 'a, D: BufRead> Patch<'a, B, D> {
 
         let base = Rc::new(RefCell::new(base));
         let cb_data: Box<Rc<RefCell<dyn ReadAndSeek>>> = Box::new(base.clone());
+        let delta = DeltaInput::new(Decompressed::new(delta)?)?;
         let raw_ptr = Box::into_raw(cb_data);
         let job = unsafe { raw::rs_patch_begin(patch_copy_cb, raw_ptr as *mut libc::c_void) };
         assert!(!job.is_null());
@@ -345,6 +807,20 @@ impl<'a, B: Read + Seek + 'a, D: BufRead> Patch<'a, B, D> {
         })
     }
 
+    /// Returns the statistics of the job so far.
+    pub fn stats(&self) -> Stats {
+        self.driver.stats()
+    }
+
+    /// Returns the fingerprint of the signature used to compute the delta, if the delta is
+    /// wrapped in an envelope.
+    ///
+    /// Comparing it with the `LoadedSignature::fingerprint` of the base file signature allows to
+    /// detect a wrong base file before patching.
+    pub fn signature_fingerprint(&self) -> Option<[u8; 32]> {
+        self.driver.get_ref().fingerprint()
+    }
+
     /// Unwraps this stream and returns the underlying streams.
     pub fn into_inner(self) -> (B, D) {
         let mut this = ManuallyDrop::new(self);
@@ -369,13 +845,19 @@ impl<'a, B: Read + Seek + 'a, D: BufRead> Patch<'a, B, D> {
         // Extract the driver using ptr::read since we can't move out of ManuallyDrop
         let driver = unsafe { ptr::read(&this.driver) };
 
-        (base.into_inner(), driver.into_inner())
+        (
+            base.into_inner(),
+            driver.into_inner().into_inner().into_inner(),
+        )
     }
 }
 
 impl<'a, B, D: BufRead> Read for Patch<'a, B, D> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        self.driver.read(buf)
+        let n = self.driver.read(buf)?;
+        let eof = n == 0 && !buf.is_empty();
+        self.driver.get_mut().check_output(&buf[..n], eof)?;
+        Ok(n)
     }
 }
 
@@ -406,6 +888,14 @@ impl Display for Error {
             Error::BadMagic => write!(fmt, "bad magic number given"),
             Error::Unimplemented => write!(fmt, "unimplemented feature"),
             Error::Internal => write!(fmt, "internal error"),
+            Error::BadBlockLen(n) => write!(fmt, "invalid signature block length {}", n),
+            Error::BadStrongLen(n) => write!(fmt, "invalid strong signature length {}", n),
+            Error::SignatureMismatch => write!(fmt, "signatures have different parameters"),
+            Error::Truncated => write!(fmt, "unexpected end of stream"),
+            Error::BadCommand(op) => write!(fmt, "unknown delta command {:#04x}", op),
+            Error::ChecksumMismatch => {
+                write!(fmt, "patched file does not match the delta checksum")
+            }
             Error::Unknown(n) => write!(fmt, "unknown error {} from native library", n),
         }
     }
@@ -413,7 +903,11 @@ impl Display for Error {
 
 impl From<io::Error> for Error {
     fn from(err: io::Error) -> Error {
-        Error::Io(err)
+        // unwrap the errors of this crate, reported through `Read` implementations
+        match err.downcast::<Error>() {
+            Ok(err) => err,
+            Err(err) => Error::Io(err),
+        }
     }
 }
 
@@ -443,6 +937,79 @@ impl SignatureType {
             SignatureType::Blake2 => raw::RS_BLAKE2_SIG_MAGIC,
         }
     }
+
+    fn from_raw(magic: raw::rs_magic_number) -> Option<Self> {
+        match magic {
+            raw::RS_MD4_SIG_MAGIC => Some(SignatureType::MD4),
+            raw::RS_BLAKE2_SIG_MAGIC => Some(SignatureType::Blake2),
+            _ => None,
+        }
+    }
+
+    // maximum length of the strong checksums for this signature type
+    fn max_strong_len(self) -> usize {
+        match self {
+            SignatureType::MD4 => 16,
+            SignatureType::Blake2 => 32,
+        }
+    }
+}
+
+impl DeltaEstimate {
+    /// Returns the fraction of the new file found in the base file, between 0 and 1.
+    ///
+    /// An empty new file has a match ratio of 1.
+    pub fn match_ratio(&self) -> f64 {
+        if self.new_len == 0 {
+            return 1.0;
+        }
+        self.copy_bytes as f64 / self.new_len as f64
+    }
+}
+
+impl Stats {
+    fn from_raw(stats: &raw::rs_stats_t) -> Self {
+        // the end time is set only when the job completes
+        let end = if stats.end != 0 {
+            stats.end
+        } else {
+            unsafe { libc::time(ptr::null_mut()) }
+        };
+        Stats {
+            lit_cmds: stats.lit_cmds as u64,
+            lit_bytes: stats.lit_bytes as u64,
+            lit_cmdbytes: stats.lit_cmdbytes as u64,
+            copy_cmds: stats.copy_cmds as u64,
+            copy_bytes: stats.copy_bytes as u64,
+            copy_cmdbytes: stats.copy_cmdbytes as u64,
+            sig_cmds: stats.sig_cmds as u64,
+            sig_bytes: stats.sig_bytes as u64,
+            false_matches: stats.false_matches as u64,
+            sig_blocks: stats.sig_blocks as u64,
+            block_len: stats.block_len,
+            in_bytes: stats.in_bytes as u64,
+            out_bytes: stats.out_bytes as u64,
+            elapsed: Duration::from_secs(end.saturating_sub(stats.start).max(0) as u64),
+        }
+    }
+
+    // Accumulates the statistics of another job.
+    fn add(&mut self, other: &Stats) {
+        self.lit_cmds += other.lit_cmds;
+        self.lit_bytes += other.lit_bytes;
+        self.lit_cmdbytes += other.lit_cmdbytes;
+        self.copy_cmds += other.copy_cmds;
+        self.copy_bytes += other.copy_bytes;
+        self.copy_cmdbytes += other.copy_cmdbytes;
+        self.sig_cmds += other.sig_cmds;
+        self.sig_bytes += other.sig_bytes;
+        self.false_matches += other.false_matches;
+        self.sig_blocks += other.sig_blocks;
+        self.block_len = other.block_len;
+        self.in_bytes += other.in_bytes;
+        self.out_bytes += other.out_bytes;
+        self.elapsed += other.elapsed;
+    }
 }
 
 impl Drop for Sumset {
@@ -464,6 +1031,11 @@ impl Deref for Sumset {
 
 unsafe impl Send for Sumset {}
 
+// SAFETY: once the hash table is built, librsync only reads the signature while computing
+// deltas. The only writes would be the lookup statistics kept in the hash table and in the
+// signature, which are compiled out by building librsync with `HASHTABLE_NSTATS`.
+unsafe impl Sync for Sumset {}
+
 extern "C" fn patch_copy_cb(
     opaque: *mut libc::c_void,
     pos: raw::rs_long_t,
@@ -483,6 +1055,20 @@ extern "C" fn patch_copy_cb(
     raw::RS_DONE
 }
 
+// Returns the square root of the file size, rounded down to a multiple of 128, and at least 256.
+fn recommended_block_len(file_len: u64) -> u64 {
+    if file_len <= 256 * 256 {
+        256
+    } else {
+        file_len.isqrt() & !127
+    }
+}
+
+// Returns the base 2 logarithm of the given value, rounded down.
+fn ln2(value: u64) -> u32 {
+    value.checked_ilog2().unwrap_or(0)
+}
+
 fn io_err<E>(kind: io::ErrorKind, e: E) -> Error
 where
     E: Into<Box<dyn error::Error + Send + Sync>>,
@@ -490,6 +1076,20 @@ where
     Error::Io(io::Error::new(kind, e))
 }
 
+// Reads until the buffer is full or the input ends, and returns the number of bytes read.
+fn read_full<R: Read + ?Sized>(input: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut pos = 0;
+    while pos < buf.len() {
+        match input.read(&mut buf[pos..]) {
+            Ok(0) => break,
+            Ok(n) => pos += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(pos)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -499,27 +1099,27 @@ mod test {
     const DATA: &str = "this is a string to be tested";
     const DATA2: &str = "this is another string to be tested";
 
-    // generated with `rdiff signature -b 10 -S 5 data data.sig`
     fn data_signature() -> Vec<u8> {
-        vec![
-            0x72, 0x73, 0x01, 0x36, 0x00, 0x00, 0x00, 0x0a, 0x00, 0x00, 0x00, 0x05, 0x1b, 0x21,
-            0x04, 0x8b, 0xad, 0x3c, 0xbd, 0x19, 0x09, 0x1d, 0x1b, 0x04, 0xf0, 0x9d, 0x1f, 0x64,
-            0x31, 0xde, 0x15, 0xf4, 0x04, 0x87, 0x60, 0x96, 0x19, 0x50, 0x39,
-        ]
+        test_util::SIG.to_vec()
+    }
+
+    // options used by `data_signature`
+    fn md4_options() -> SignatureOptions {
+        SignatureOptions::builder(SignatureType::MD4)
+            .block_len(10)
+            .strong_len(5)
+            .build()
+            .unwrap()
     }
 
-    // generated with `rdiff delta data.sig data2 data2.delta`
     fn data2_delta() -> Vec<u8> {
-        vec![
-            0x72, 0x73, 0x02, 0x36, 0x10, 0x74, 0x68, 0x69, 0x73, 0x20, 0x69, 0x73, 0x20, 0x61,
-            0x6e, 0x6f, 0x74, 0x68, 0x65, 0x72, 0x20, 0x45, 0x0a, 0x13, 0x00,
-        ]
+        test_util::DELTA.to_vec()
     }
 
     #[test]
     fn signature() {
         let cursor = Cursor::new(DATA);
-        let mut sig = Signature::with_options(cursor, 10, 5, SignatureType::MD4).unwrap();
+        let mut sig = Signature::with_options(cursor, md4_options()).unwrap();
         let mut signature = Vec::new();
         let read = sig.read_to_end(&mut signature).unwrap();
         assert_eq!(read, signature.len());
@@ -555,7 +1155,7 @@ mod test {
     fn integration() {
         let base = Cursor::new(DATA);
         let new = Cursor::new(DATA2);
-        let mut sig = Signature::with_options(base, 10, 5, SignatureType::MD4).unwrap();
+        let mut sig = Signature::with_options(base, md4_options()).unwrap();
         let delta = Delta::new(new, &mut sig).unwrap();
         let base = Cursor::new(DATA);
         let mut patch = Patch::new(base, delta).unwrap();
@@ -564,6 +1164,232 @@ mod test {
         assert_eq!(computed_new, DATA2);
     }
 
+    #[test]
+    fn invalid_options() {
+        match SignatureOptions::builder(SignatureType::Blake2)
+            .block_len(0)
+            .build()
+        {
+            Err(Error::BadBlockLen(0)) => (),
+            res => panic!("unexpected result {:?}", res),
+        }
+        match SignatureOptions::builder(SignatureType::MD4)
+            .strong_len(20)
+            .build()
+        {
+            Err(Error::BadStrongLen(20)) => (),
+            res => panic!("unexpected result {:?}", res),
+        }
+        let opts = SignatureOptions::builder(SignatureType::Blake2)
+            .strong_len(20)
+            .build()
+            .unwrap();
+        assert_eq!(opts.strong_len(), 20);
+        assert_eq!(opts.block_len(), 2048);
+        assert_eq!(
+            SignatureOptions::builder(SignatureType::Blake2)
+                .build()
+                .unwrap(),
+            SignatureOptions::default()
+        );
+    }
+
+    #[test]
+    fn recommended_options() {
+        let small = SignatureOptions::recommended(1000, SignatureType::Blake2).unwrap();
+        assert_eq!(small.block_len(), 256);
+        assert_eq!(small.strong_len(), 6);
+
+        let large = SignatureOptions::recommended(1 << 30, SignatureType::MD4).unwrap();
+        assert_eq!(large.block_len(), 1 << 15);
+        assert_eq!(large.strong_len(), 8);
+        assert_eq!(large.sig_type(), SignatureType::MD4);
+    }
+
+    #[test]
+    fn local_options() {
+        for len in [0, 1000, 1 << 20, 1 << 30, u64::MAX] {
+            let local = SignatureOptions::local(len, SignatureType::Blake2);
+            let recommended = SignatureOptions::recommended(len, SignatureType::Blake2).unwrap();
+            assert!(local.block_len() < recommended.block_len());
+            assert!(local.block_len() >= 64);
+            assert!(local.strong_len() >= recommended.strong_len());
+        }
+        let large = SignatureOptions::local(1 << 30, SignatureType::Blake2);
+        assert_eq!(large.block_len(), 1 << 13);
+    }
+
+    #[test]
+    fn for_len() {
+        let mut sig = Signature::for_len(Cursor::new(DATA), DATA.len() as u64).unwrap();
+        let delta = Delta::new(Cursor::new(DATA2), &mut sig).unwrap();
+        let mut patch = Patch::new(Cursor::new(DATA), delta).unwrap();
+        let mut computed_new = String::new();
+        patch.read_to_string(&mut computed_new).unwrap();
+        assert_eq!(computed_new, DATA2);
+    }
+
+    #[test]
+    fn truncated_signature() {
+        let mut sig = Vec::new();
+        truncate_signature(&mut Cursor::new(data_signature()), &mut sig, 3).unwrap();
+        let delta = Delta::new(Cursor::new(DATA2), &mut Cursor::new(sig)).unwrap();
+        let mut patch = Patch::new(Cursor::new(DATA), delta).unwrap();
+        let mut computed_new = String::new();
+        patch.read_to_string(&mut computed_new).unwrap();
+        assert_eq!(computed_new, DATA2);
+    }
+
+    #[test]
+    fn written_delta() {
+        let mut writer = DeltaWriter::new(Vec::new()).unwrap();
+        writer.copy(0, 8).unwrap();
+        writer.literal(b"another ").unwrap();
+        writer.copy(10, 19).unwrap();
+        writer.finish().unwrap();
+        let mut patch = Patch::new(Cursor::new(DATA), Cursor::new(writer.into_inner())).unwrap();
+        let mut computed_new = String::new();
+        patch.read_to_string(&mut computed_new).unwrap();
+        assert_eq!(computed_new, DATA2);
+    }
+
+    #[test]
+    fn stats() {
+        let mut sig = Signature::with_options(Cursor::new(DATA), md4_options()).unwrap();
+        let mut signature = Vec::new();
+        sig.read_to_end(&mut signature).unwrap();
+        let stats = sig.stats();
+        assert_eq!(stats.in_bytes, DATA.len() as u64);
+        assert_eq!(stats.out_bytes, signature.len() as u64);
+
+        let mut job = Delta::new(Cursor::new(DATA2), &mut Cursor::new(signature)).unwrap();
+        let mut delta = Vec::new();
+        job.read_to_end(&mut delta).unwrap();
+        let stats = job.stats();
+        assert_eq!(stats.lit_cmds, 1);
+        assert_eq!(stats.lit_bytes, 16);
+        assert_eq!(stats.copy_cmds, 1);
+        assert_eq!(stats.copy_bytes, 19);
+        assert_eq!(stats.in_bytes, DATA2.len() as u64);
+        assert_eq!(stats.out_bytes, delta.len() as u64);
+
+        let mut patch = Patch::new(Cursor::new(DATA), Cursor::new(delta)).unwrap();
+        let mut computed_new = Vec::new();
+        patch.read_to_end(&mut computed_new).unwrap();
+        assert_eq!(patch.stats().out_bytes, DATA2.len() as u64);
+    }
+
+    #[test]
+    fn composed_deltas() {
+        const DATA3: &str = "this is yet another string to be tested again";
+        let mut ab = Vec::new();
+        let mut sig = Signature::with_options(Cursor::new(DATA), md4_options()).unwrap();
+        Delta::new(Cursor::new(DATA2), &mut sig)
+            .unwrap()
+            .read_to_end(&mut ab)
+            .unwrap();
+        let mut bc = Vec::new();
+        let mut sig = Signature::with_options(Cursor::new(DATA2), md4_options()).unwrap();
+        Delta::new(Cursor::new(DATA3), &mut sig)
+            .unwrap()
+            .read_to_end(&mut bc)
+            .unwrap();
+
+        let mut ac = Vec::new();
+        compose_deltas(&mut Cursor::new(ab), &mut Cursor::new(bc), &mut ac).unwrap();
+        let mut patch = Patch::new(Cursor::new(DATA), Cursor::new(ac)).unwrap();
+        let mut computed_new = String::new();
+        patch.read_to_string(&mut computed_new).unwrap();
+        assert_eq!(computed_new, DATA3);
+    }
+
+    #[test]
+    fn envelope() {
+        let sig = Arc::new(LoadedSignature::new(&mut Cursor::new(data_signature())).unwrap());
+        let mut job = Delta::with_envelope(Cursor::new(DATA2), sig.clone()).unwrap();
+        let mut delta = Vec::new();
+        job.read_to_end(&mut delta).unwrap();
+
+        let mut patch = Patch::new(Cursor::new(DATA), Cursor::new(&delta)).unwrap();
+        assert_eq!(patch.signature_fingerprint(), Some(sig.fingerprint()));
+        let mut computed_new = String::new();
+        patch.read_to_string(&mut computed_new).unwrap();
+        assert_eq!(computed_new, DATA2);
+
+        // patching the wrong base file
+        let wrong = "THIS IS a string to be tested";
+        let mut patch = Patch::new(Cursor::new(wrong), Cursor::new(&delta)).unwrap();
+        let err = patch.read_to_end(&mut Vec::new()).unwrap_err();
+        assert!(matches!(Error::from(err), Error::ChecksumMismatch));
+
+        // plain deltas have no fingerprint
+        let patch = Patch::new(Cursor::new(DATA), Cursor::new(data2_delta())).unwrap();
+        assert_eq!(patch.signature_fingerprint(), None);
+    }
+
+    #[cfg(any(feature = "zstd", feature = "gzip"))]
+    #[test]
+    fn compressed() {
+        let methods = [
+            #[cfg(feature = "gzip")]
+            Compression::Gzip(6),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(3),
+        ];
+        for &method in &methods {
+            let sig = Signature::with_options(Cursor::new(DATA), md4_options()).unwrap();
+            let mut signature = Vec::new();
+            Compressed::new(sig, method)
+                .unwrap()
+                .read_to_end(&mut signature)
+                .unwrap();
+            let delta = Delta::new(Cursor::new(DATA2), &mut Cursor::new(signature)).unwrap();
+            let mut delta_data = Vec::new();
+            Compressed::new(delta, method)
+                .unwrap()
+                .read_to_end(&mut delta_data)
+                .unwrap();
+            let mut patch = Patch::new(Cursor::new(DATA), Cursor::new(delta_data)).unwrap();
+            let mut computed_new = String::new();
+            patch.read_to_string(&mut computed_new).unwrap();
+            assert_eq!(computed_new, DATA2);
+        }
+    }
+
+    #[test]
+    fn from_files() {
+        for opts in [None, Some(md4_options())] {
+            let mut job = Delta::from_files(Cursor::new(DATA), Cursor::new(DATA2), opts).unwrap();
+            let mut delta = Vec::new();
+            job.read_to_end(&mut delta).unwrap();
+            let mut patch = Patch::new(Cursor::new(DATA), Cursor::new(delta)).unwrap();
+            let mut computed_new = String::new();
+            patch.read_to_string(&mut computed_new).unwrap();
+            assert_eq!(computed_new, DATA2);
+        }
+    }
+
+    #[test]
+    fn estimate() {
+        let job = Delta::new(Cursor::new(DATA2), &mut Cursor::new(data_signature())).unwrap();
+        let estimate = job.estimate().unwrap();
+        assert_eq!(
+            estimate,
+            DeltaEstimate {
+                new_len: DATA2.len() as u64,
+                delta_len: data2_delta().len() as u64,
+                literal_bytes: 16,
+                copy_bytes: 19,
+            }
+        );
+        assert!((estimate.match_ratio() - 19.0 / 35.0).abs() < 1e-9);
+
+        let sig = Arc::new(LoadedSignature::new(&mut Cursor::new(data_signature())).unwrap());
+        let job = Delta::with_envelope(Cursor::new(DATA2), sig).unwrap();
+        let enveloped = job.estimate().unwrap();
+        assert_eq!(enveloped.delta_len, estimate.delta_len + ENVELOPE_OVERHEAD);
+    }
+
     #[test]
     fn send_sig() {
         let cursor = Cursor::new(DATA);
@@ -587,6 +1413,25 @@ mod test {
         t.join().unwrap();
     }
 
+    #[test]
+    fn shared_signature() {
+        let sig = Arc::new(LoadedSignature::new(&mut Cursor::new(data_signature())).unwrap());
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let sig = sig.clone();
+                thread::spawn(move || {
+                    let mut job = Delta::with_signature(Cursor::new(DATA2), sig).unwrap();
+                    let mut delta = Vec::new();
+                    job.read_to_end(&mut delta).unwrap();
+                    delta
+                })
+            })
+            .collect();
+        for t in threads {
+            assert_eq!(t.join().unwrap(), data2_delta());
+        }
+    }
+
     #[test]
     fn send_patch() {
         let base = Cursor::new(DATA);
@@ -603,8 +1448,12 @@ mod test {
     #[test]
     fn trivial_large_file() {
         let data = vec![0; 65536];
-        let mut sig =
-            Signature::with_options(Cursor::new(&data), 16384, 5, SignatureType::MD4).unwrap();
+        let opts = SignatureOptions::builder(SignatureType::MD4)
+            .block_len(16384)
+            .strong_len(5)
+            .build()
+            .unwrap();
+        let mut sig = Signature::with_options(Cursor::new(&data), opts).unwrap();
         let delta = Delta::new(Cursor::new(&data), &mut sig).unwrap();
         let mut computed_new = vec![];
         Patch::new(Cursor::new(&data), delta)
@@ -0,0 +1,27 @@
+use std::io::{self, Read, Write};
+
+/// Reads a big endian unsigned integer of `width` bytes.
+pub fn read_uint<R: Read + ?Sized>(input: &mut R, width: usize) -> io::Result<u64> {
+    debug_assert!(width <= 8);
+    let mut buf = [0; 8];
+    input.read_exact(&mut buf[8 - width..])?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+/// Reads a big endian 32 bits unsigned integer.
+pub fn read_u32<R: Read + ?Sized>(input: &mut R) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    input.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+/// Writes a big endian unsigned integer of `width` bytes.
+pub fn write_uint<W: Write + ?Sized>(output: &mut W, value: u64, width: usize) -> io::Result<()> {
+    debug_assert!(width <= 8);
+    output.write_all(&value.to_be_bytes()[8 - width..])
+}
+
+/// Writes a big endian 32 bits unsigned integer.
+pub fn write_u32<W: Write + ?Sized>(output: &mut W, value: u32) -> io::Result<()> {
+    output.write_all(&value.to_be_bytes())
+}
@@ -0,0 +1,106 @@
+//! Serialization support, enabled by the `serde` feature.
+//!
+//! Most types simply derive `Serialize` and `Deserialize`. This module contains the helpers for
+//! the types which need to be validated while deserialized.
+
+use serde::Deserialize;
+
+use crate::{Error, SignatureHeader, SignatureOptions, SignatureType};
+
+// Signature options, as deserialized before validation.
+#[derive(Clone, Copy, Deserialize)]
+pub struct SignatureOptionsDef {
+    block_len: usize,
+    strong_len: usize,
+    sig_type: SignatureType,
+}
+
+impl TryFrom<SignatureOptionsDef> for SignatureOptions {
+    type Error = Error;
+
+    fn try_from(def: SignatureOptionsDef) -> Result<Self, Error> {
+        SignatureOptions::builder(def.sig_type)
+            .block_len(def.block_len)
+            .strong_len(def.strong_len)
+            .build()
+    }
+}
+
+// Signature header, as deserialized before validation.
+#[derive(Clone, Copy, Deserialize)]
+pub struct SignatureHeaderDef {
+    sig_type: SignatureType,
+    block_len: usize,
+    strong_len: usize,
+}
+
+impl TryFrom<SignatureHeaderDef> for SignatureHeader {
+    type Error = Error;
+
+    fn try_from(def: SignatureHeaderDef) -> Result<Self, Error> {
+        SignatureHeader {
+            sig_type: def.sig_type,
+            block_len: def.block_len,
+            strong_len: def.strong_len,
+        }
+        .validate()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{SignatureBlock, SignatureHeader, SignatureOptions, SignatureType};
+
+    #[test]
+    fn options() {
+        let opts = SignatureOptions::builder(SignatureType::Blake2)
+            .block_len(512)
+            .strong_len(8)
+            .build()
+            .unwrap();
+        let json = serde_json::to_string(&opts).unwrap();
+        assert_eq!(
+            json,
+            r#"{"block_len":512,"strong_len":8,"sig_type":"Blake2"}"#
+        );
+        assert_eq!(
+            serde_json::from_str::<SignatureOptions>(&json).unwrap(),
+            opts
+        );
+
+        let invalid = r#"{"block_len":512,"strong_len":20,"sig_type":"MD4"}"#;
+        assert!(serde_json::from_str::<SignatureOptions>(invalid).is_err());
+    }
+
+    #[test]
+    fn header_and_blocks() {
+        let header = SignatureHeader {
+            sig_type: SignatureType::MD4,
+            block_len: 10,
+            strong_len: 5,
+        };
+        let json = serde_json::to_string(&header).unwrap();
+        assert_eq!(
+            serde_json::from_str::<SignatureHeader>(&json).unwrap(),
+            header
+        );
+        for invalid in [
+            r#"{"sig_type":"MD4","block_len":0,"strong_len":5}"#,
+            r#"{"sig_type":"MD4","block_len":10,"strong_len":0}"#,
+            r#"{"sig_type":"MD4","block_len":10,"strong_len":17}"#,
+        ] {
+            assert!(serde_json::from_str::<SignatureHeader>(invalid).is_err());
+        }
+
+        let blocks = vec![SignatureBlock {
+            index: 0,
+            weak_sum: 0x1b21_048b,
+            strong_sum: vec![0xad, 0x3c, 0xbd, 0x19, 0x09],
+        }];
+        let json = serde_json::to_string(&blocks).unwrap();
+        assert_eq!(
+            serde_json::from_str::<Vec<SignatureBlock>>(&json).unwrap(),
+            blocks
+        );
+    }
+}
@@ -0,0 +1,430 @@
+//! Parsing of signature streams.
+//!
+//! A signature stream starts with a header, followed by the checksums of every block of the base
+//! file. Each block is described by a 4 bytes weak checksum and a strong checksum, truncated to
+//! the length specified in the header.
+
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, BufWriter, Read, Write};
+use std::ops::{Range, RangeInclusive};
+
+use crate::command::truncated;
+use crate::netint;
+use crate::{Error, Result, SignatureType, raw, read_full};
+
+/// The header of a signature stream.
+///
+/// The header describes the format of a signature: its type, the size of the blocks in which the
+/// base file has been split, and the length of the strong checksum of each block. It is possible
+/// to inspect it without loading the whole signature.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(try_from = "crate::serde_impl::SignatureHeaderDef")
+)]
+pub struct SignatureHeader {
+    /// The signature format.
+    pub sig_type: SignatureType,
+    /// The size of each block of the base file, in bytes.
+    pub block_len: usize,
+    /// The length of each strong checksum, in bytes.
+    pub strong_len: usize,
+}
+
+impl SignatureHeader {
+    /// The size of a serialized header, in bytes.
+    pub const LEN: usize = 12;
+
+    /// Parses the header at the start of a signature stream.
+    ///
+    /// Only the header bytes are consumed from the given input, leaving it positioned at the
+    /// first block. A stream with an unknown magic number is reported as `Error::BadMagic`, a
+    /// stream ending before the end of the header as `Error::Truncated`, and invalid lengths as
+    /// `Error::BadBlockLen` or `Error::BadStrongLen`.
+    pub fn parse<R: Read + ?Sized>(input: &mut R) -> Result<Self> {
+        let magic = netint::read_u32(input).map_err(truncated)?;
+        let sig_type =
+            SignatureType::from_raw(magic as raw::rs_magic_number).ok_or(Error::BadMagic)?;
+        let block_len = netint::read_u32(input).map_err(truncated)? as usize;
+        let strong_len = netint::read_u32(input).map_err(truncated)? as usize;
+        SignatureHeader {
+            sig_type,
+            block_len,
+            strong_len,
+        }
+        .validate()
+    }
+
+    // Checks that the lengths of the header are consistent with its signature type.
+    pub(crate) fn validate(self) -> Result<Self> {
+        if self.block_len == 0 || u32::try_from(self.block_len).is_err() {
+            return Err(Error::BadBlockLen(self.block_len));
+        }
+        if self.strong_len == 0 || self.strong_len > self.sig_type.max_strong_len() {
+            return Err(Error::BadStrongLen(self.strong_len));
+        }
+        Ok(self)
+    }
+
+    // Writes the serialized header.
+    fn write<W: Write + ?Sized>(&self, output: &mut W) -> io::Result<()> {
+        netint::write_u32(output, self.sig_type.as_raw() as u32)?;
+        netint::write_u32(output, self.block_len as u32)?;
+        netint::write_u32(output, self.strong_len as u32)
+    }
+
+    /// Returns the size of each serialized block signature, in bytes.
+    pub fn block_sig_len(&self) -> usize {
+        4 + self.strong_len
+    }
+
+    /// Returns the number of blocks in a signature, given its total length in bytes.
+    ///
+    /// `None` is returned if the given length is not consistent with this header.
+    pub fn block_count(&self, sig_len: u64) -> Option<u64> {
+        let body = sig_len.checked_sub(Self::LEN as u64)?;
+        let block_sig_len = self.block_sig_len() as u64;
+        if body % block_sig_len != 0 {
+            return None;
+        }
+        Some(body / block_sig_len)
+    }
+
+    /// Returns the range of possible sizes of the base file, given the total signature length.
+    ///
+    /// The exact size cannot be determined, because the last block of the file may be shorter
+    /// than `block_len`. `None` is returned if the given length is not consistent with this
+    /// header.
+    pub fn file_len_range(&self, sig_len: u64) -> Option<RangeInclusive<u64>> {
+        let count = self.block_count(sig_len)?;
+        if count == 0 {
+            return Some(0..=0);
+        }
+        let block_len = self.block_len as u64;
+        Some((count - 1) * block_len + 1..=count * block_len)
+    }
+}
+
+impl Display for SignatureHeader {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        write!(
+            fmt,
+            "{:?} signature, block_len={} strong_len={}",
+            self.sig_type, self.block_len, self.strong_len
+        )
+    }
+}
+
+/// The checksums of a single block of the base file.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SignatureBlock {
+    /// The index of the block in the base file.
+    pub index: u64,
+    /// The weak (rolling) checksum of the block.
+    pub weak_sum: u32,
+    /// The strong checksum of the block, truncated to the length given in the header.
+    #[cfg_attr(feature = "serde", serde(with = "serde_bytes"))]
+    pub strong_sum: Vec<u8>,
+}
+
+impl Display for SignatureBlock {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        write!(
+            fmt,
+            "block {} weak={:08x} strong=",
+            self.index, self.weak_sum
+        )?;
+        for b in &self.strong_sum {
+            write!(fmt, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+/// A streaming reader of the blocks of a signature.
+///
+/// This type takes a `Read` stream for a signature, parses its header and then iterates over the
+/// checksums of each block, without loading the whole signature in memory.
+pub struct SignatureBlocks<R> {
+    input: R,
+    header: SignatureHeader,
+    next_index: u64,
+    done: bool,
+}
+
+impl<R: Read> SignatureBlocks<R> {
+    /// Creates a new block reader, by parsing the header of the given signature stream.
+    pub fn new(mut input: R) -> Result<Self> {
+        let header = SignatureHeader::parse(&mut input)?;
+        Ok(SignatureBlocks {
+            input,
+            header,
+            next_index: 0,
+            done: false,
+        })
+    }
+
+    /// Returns the header of the signature.
+    pub fn header(&self) -> SignatureHeader {
+        self.header
+    }
+
+    /// Unwraps this reader, returning the underlying signature stream.
+    pub fn into_inner(self) -> R {
+        self.input
+    }
+
+    fn read_block(&mut self) -> Result<Option<SignatureBlock>> {
+        let mut buf = vec![0; self.header.block_sig_len()];
+        match read_full(&mut self.input, &mut buf)? {
+            0 => return Ok(None),
+            n if n < buf.len() => return Err(Error::Truncated),
+            _ => (),
+        }
+        let strong_sum = buf.split_off(4);
+        let block = SignatureBlock {
+            index: self.next_index,
+            weak_sum: u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]),
+            strong_sum,
+        };
+        self.next_index += 1;
+        Ok(Some(block))
+    }
+}
+
+impl<R: Read> Iterator for SignatureBlocks<R> {
+    type Item = Result<SignatureBlock>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.read_block() {
+            Ok(Some(block)) => Some(Ok(block)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// The differences between two signatures, as ranges of block indexes.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SignatureDiff {
+    /// The blocks present in both signatures, but with different checksums.
+    pub changed: Vec<Range<u64>>,
+    /// The blocks present only in the second signature.
+    pub added: Vec<Range<u64>>,
+    /// The blocks present only in the first signature.
+    pub removed: Vec<Range<u64>>,
+}
+
+/// Compares two signatures, and reports which blocks differ between them.
+///
+/// This function consumes both the given signature streams, which must have been generated with
+/// the same parameters (signature type, block length and strong checksum length), otherwise
+/// `Error::SignatureMismatch` is returned. Blocks are compared by position, so the result tells
+/// which parts of the base file changed, without the need of the files themselves.
+pub fn signature_diff<A, B>(a: &mut A, b: &mut B) -> Result<SignatureDiff>
+where
+    A: Read + ?Sized,
+    B: Read + ?Sized,
+{
+    let mut a = SignatureBlocks::new(a)?;
+    let mut b = SignatureBlocks::new(b)?;
+    if a.header() != b.header() {
+        return Err(Error::SignatureMismatch);
+    }
+
+    let mut diff = SignatureDiff::default();
+    loop {
+        match (a.next().transpose()?, b.next().transpose()?) {
+            (Some(block_a), Some(block_b)) => {
+                if block_a != block_b {
+                    push_index(&mut diff.changed, block_a.index);
+                }
+            }
+            (Some(block_a), None) => push_index(&mut diff.removed, block_a.index),
+            (None, Some(block_b)) => push_index(&mut diff.added, block_b.index),
+            (None, None) => return Ok(diff),
+        }
+    }
+}
+
+/// Rewrites a signature, by truncating its strong checksums to a shorter length.
+///
+/// This function consumes the given signature stream and writes to the given output the same
+/// signature, with strong checksums truncated to `strong_len` bytes. This makes the signature
+/// smaller, at the cost of a higher probability of false matches. The new length must be non-zero
+/// and not larger than the current one, otherwise `Error::BadStrongLen` is returned. In case of
+/// success, the number of bytes written is returned.
+pub fn truncate_signature<R, W>(input: &mut R, output: &mut W, strong_len: usize) -> Result<u64>
+where
+    R: Read + ?Sized,
+    W: Write + ?Sized,
+{
+    let blocks = SignatureBlocks::new(input)?;
+    let mut header = blocks.header();
+    if strong_len == 0 || strong_len > header.strong_len {
+        return Err(Error::BadStrongLen(strong_len));
+    }
+    header.strong_len = strong_len;
+
+    let mut output = BufWriter::new(output);
+    header.write(&mut output)?;
+    let mut written = SignatureHeader::LEN as u64;
+    for block in blocks {
+        let block = block?;
+        netint::write_u32(&mut output, block.weak_sum)?;
+        output.write_all(&block.strong_sum[..strong_len])?;
+        written += header.block_sig_len() as u64;
+    }
+    output.flush()?;
+    Ok(written)
+}
+
+// Adds an index to a list of ranges, by extending the last range if contiguous.
+fn push_index(ranges: &mut Vec<Range<u64>>, index: u64) {
+    match ranges.last_mut() {
+        Some(last) if last.end == index => last.end += 1,
+        _ => ranges.push(index..index + 1),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::SIG;
+    use std::io::Cursor;
+
+    #[test]
+    fn parse() {
+        let mut input = Cursor::new(SIG);
+        let header = SignatureHeader::parse(&mut input).unwrap();
+        assert_eq!(header.sig_type, SignatureType::MD4);
+        assert_eq!(header.block_len, 10);
+        assert_eq!(header.strong_len, 5);
+        assert_eq!(input.position(), SignatureHeader::LEN as u64);
+
+        assert_eq!(header.block_count(39), Some(3));
+        assert_eq!(header.file_len_range(39), Some(21..=30));
+        assert_eq!(header.block_count(12), Some(0));
+        assert_eq!(header.file_len_range(12), Some(0..=0));
+        assert_eq!(header.block_count(40), None);
+        assert_eq!(header.block_count(4), None);
+    }
+
+    #[test]
+    fn bad_magic() {
+        let mut sig = SIG;
+        sig[3] = 0x99;
+        match SignatureHeader::parse(&mut Cursor::new(sig)) {
+            Err(Error::BadMagic) => (),
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert!(matches!(
+            SignatureHeader::parse(&mut Cursor::new(&SIG[..10])),
+            Err(Error::Truncated)
+        ));
+        let mut sig = SIG;
+        sig[7] = 0;
+        assert!(matches!(
+            SignatureHeader::parse(&mut Cursor::new(sig)),
+            Err(Error::BadBlockLen(0))
+        ));
+        let mut sig = SIG;
+        sig[11] = 17;
+        assert!(matches!(
+            SignatureHeader::parse(&mut Cursor::new(sig)),
+            Err(Error::BadStrongLen(17))
+        ));
+    }
+
+    #[test]
+    fn blocks() {
+        let blocks = SignatureBlocks::new(Cursor::new(SIG)).unwrap();
+        assert_eq!(blocks.header().block_len, 10);
+        let blocks: Vec<_> = blocks.map(|b| b.unwrap()).collect();
+        assert_eq!(blocks.len(), 3);
+        assert_eq!(
+            blocks[0],
+            SignatureBlock {
+                index: 0,
+                weak_sum: 0x1b21_048b,
+                strong_sum: vec![0xad, 0x3c, 0xbd, 0x19, 0x09],
+            }
+        );
+        assert_eq!(blocks[2].index, 2);
+        assert_eq!(blocks[2].weak_sum, 0x15f4_0487);
+    }
+
+    #[test]
+    fn truncated_blocks() {
+        let blocks = SignatureBlocks::new(Cursor::new(&SIG[..34])).unwrap();
+        let blocks: Vec<_> = blocks.collect();
+        assert_eq!(blocks.len(), 3);
+        assert!(blocks[1].is_ok());
+        assert!(matches!(blocks[2], Err(Error::Truncated)));
+    }
+
+    #[test]
+    fn truncate() {
+        let mut out = Vec::new();
+        let written = truncate_signature(&mut Cursor::new(SIG), &mut out, 3).unwrap();
+        assert_eq!(written, out.len() as u64);
+        assert_eq!(out.len(), SignatureHeader::LEN + 3 * 7);
+
+        let mut blocks = SignatureBlocks::new(Cursor::new(out)).unwrap();
+        assert_eq!(blocks.header().strong_len, 3);
+        let first = blocks.next().unwrap().unwrap();
+        assert_eq!(first.weak_sum, 0x1b21_048b);
+        assert_eq!(first.strong_sum, vec![0xad, 0x3c, 0xbd]);
+        assert_eq!(blocks.count(), 2);
+
+        match truncate_signature(&mut Cursor::new(SIG), &mut Vec::new(), 6) {
+            Err(Error::BadStrongLen(6)) => (),
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn diff() {
+        let mut changed = SIG;
+        changed[SignatureHeader::LEN + 9 + 4] ^= 0xff;
+        let diff = signature_diff(&mut Cursor::new(SIG), &mut Cursor::new(changed)).unwrap();
+        assert_eq!(diff.changed, vec![1..2]);
+        assert!(diff.added.is_empty() && diff.removed.is_empty());
+
+        let diff = signature_diff(&mut Cursor::new(&SIG[..21]), &mut Cursor::new(SIG)).unwrap();
+        assert!(diff.changed.is_empty());
+        assert_eq!(diff.added, vec![1..3]);
+
+        let diff = signature_diff(&mut Cursor::new(SIG), &mut Cursor::new(&SIG[..12])).unwrap();
+        assert_eq!(diff.removed, vec![0..3]);
+
+        let diff = signature_diff(&mut Cursor::new(SIG), &mut Cursor::new(SIG)).unwrap();
+        assert_eq!(diff, SignatureDiff::default());
+    }
+
+    #[test]
+    fn diff_mismatch() {
+        let mut other = SIG;
+        other[7] = 0x0b;
+        match signature_diff(&mut Cursor::new(SIG), &mut Cursor::new(other)) {
+            Err(Error::SignatureMismatch) => (),
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+}
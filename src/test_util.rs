@@ -0,0 +1,37 @@
+//! Helpers shared by the unit tests.
+
+use std::io::Cursor;
+
+use crate::{DeltaReader, DeltaWriter, Op, Result};
+
+// signature of "this is a string to be tested", generated with
+// `rdiff signature -b 10 -S 5 data data.sig`
+pub const SIG: [u8; 39] = [
+    0x72, 0x73, 0x01, 0x36, 0x00, 0x00, 0x00, 0x0a, 0x00, 0x00, 0x00, 0x05, 0x1b, 0x21, 0x04, 0x8b,
+    0xad, 0x3c, 0xbd, 0x19, 0x09, 0x1d, 0x1b, 0x04, 0xf0, 0x9d, 0x1f, 0x64, 0x31, 0xde, 0x15, 0xf4,
+    0x04, 0x87, 0x60, 0x96, 0x19, 0x50, 0x39,
+];
+
+// delta to "this is another string to be tested", generated with
+// `rdiff delta data.sig data2 data2.delta`
+pub const DELTA: [u8; 25] = [
+    0x72, 0x73, 0x02, 0x36, 0x10, 0x74, 0x68, 0x69, 0x73, 0x20, 0x69, 0x73, 0x20, 0x61, 0x6e, 0x6f,
+    0x74, 0x68, 0x65, 0x72, 0x20, 0x45, 0x0a, 0x13, 0x00,
+];
+
+// Encodes the given operations as a delta.
+pub fn encode(ops: &[Op]) -> Vec<u8> {
+    let mut writer = DeltaWriter::new(Vec::new()).unwrap();
+    for op in ops {
+        writer.write_op(op).unwrap();
+    }
+    let written = writer.finish().unwrap();
+    let delta = writer.into_inner();
+    assert_eq!(written, delta.len() as u64);
+    delta
+}
+
+// Decodes the operations of the given delta.
+pub fn decode(delta: &[u8]) -> Result<Vec<Op>> {
+    DeltaReader::new(Cursor::new(delta))?.collect()
+}
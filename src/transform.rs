@@ -0,0 +1,436 @@
+//! Transformations of delta streams.
+//!
+//! These functions work on the decoded operations of deltas, without the need of the base file.
+
+use std::io::{self, BufWriter, Read, Write};
+use std::ops::Range;
+
+use crate::command::{Command, DeltaReader, DeltaWriter};
+use crate::{Error, Result, io_err};
+
+/// Composes two consecutive deltas into a single one.
+///
+/// Given a delta from `a` to `b` (`ab` parameter) and a delta from `b` to `c` (`bc` parameter),
+/// this function writes to the given output a delta from `a` to `c`, without the need of any of
+/// the files. Copy commands of `bc` are rewritten in terms of the content of `b` described by
+/// `ab`. In case of success, the number of bytes written is returned.
+///
+/// The literal data of `ab` is kept in memory, while `bc` is processed as a stream.
+pub fn compose_deltas<A, B, W>(ab: &mut A, bc: &mut B, output: &mut W) -> Result<u64>
+where
+    A: Read + ?Sized,
+    B: Read + ?Sized,
+    W: Write + ?Sized,
+{
+    let map = OutputMap::new(DeltaReader::new(ab)?)?;
+    let mut bc = DeltaReader::new(bc)?;
+    let mut writer = DeltaWriter::new(BufWriter::new(output))?;
+    while let Some(cmd) = bc.next_command()? {
+        match cmd {
+            Command::Literal(len) => writer.literal(&bc.read_literal(len)?)?,
+            Command::Copy { offset, len } => map.copy(offset, len, &mut writer)?,
+            Command::End => (),
+        }
+    }
+    writer.finish()
+}
+
+/// Re-encodes a delta in its most compact form.
+///
+/// The operations of the given delta are decoded and encoded again, by merging adjacent literal
+/// commands and contiguous copy commands, dropping empty commands, and using the smallest integer
+/// widths. The base file is not needed, so copy commands are never turned into literal data or
+/// vice versa. Applying the optimized delta produces exactly the same output as the original one.
+/// In case of success, the number of bytes written is returned.
+pub fn optimize_delta<R, W>(input: &mut R, output: &mut W) -> Result<u64>
+where
+    R: Read + ?Sized,
+    W: Write + ?Sized,
+{
+    let mut reader = DeltaReader::new(input)?;
+    let mut writer = DeltaWriter::new(BufWriter::new(output))?;
+    while let Some(cmd) = reader.next_command()? {
+        match cmd {
+            Command::Literal(len) => writer.literal(&reader.read_literal(len)?)?,
+            Command::Copy { offset, len } => writer.copy(offset, len)?,
+            Command::End => (),
+        }
+    }
+    writer.finish()
+}
+
+/// Extracts a range of the output of a delta, as a smaller delta.
+///
+/// Given a delta and a range of the file it produces, this function writes to the given output a
+/// delta producing only that range, from the same base file. Commands overlapping the bounds of
+/// the range are cut, and the ones outside of it are dropped. The input delta is read only up to
+/// the end of the range. In case of success, the number of bytes written is returned.
+///
+/// An error is returned if the range is reversed, or if it ends after the output of the delta.
+pub fn slice_delta<R, W>(delta: &mut R, output: &mut W, range: Range<u64>) -> Result<u64>
+where
+    R: Read + ?Sized,
+    W: Write + ?Sized,
+{
+    if range.start > range.end {
+        return Err(io_err(io::ErrorKind::InvalidInput, "invalid delta range"));
+    }
+    let mut reader = DeltaReader::new(delta)?;
+    let mut writer = DeltaWriter::new(BufWriter::new(output))?;
+    let mut pos = 0u64;
+    while pos < range.end {
+        let cmd = match reader.next_command()? {
+            Some(Command::End) | None => break,
+            Some(cmd) => cmd,
+        };
+        let len = match cmd {
+            Command::Literal(len) | Command::Copy { len, .. } => len,
+            Command::End => 0,
+        };
+        // the part of the command within the range, relative to its start
+        let skip = range.start.saturating_sub(pos).min(len);
+        let take = (range.end - pos).min(len) - skip;
+        match cmd {
+            Command::Literal(_) if take == 0 => reader.skip_literal(len)?,
+            Command::Literal(_) => {
+                reader.skip_literal(skip)?;
+                writer.literal(&reader.read_literal(take)?)?;
+                reader.skip_literal(len - skip - take)?;
+            }
+            Command::Copy { offset, .. } => {
+                let offset = offset.checked_add(skip).ok_or_else(copy_out_of_range)?;
+                writer.copy(offset, take)?;
+            }
+            Command::End => (),
+        }
+        pos = pos.checked_add(len).ok_or_else(output_too_large)?;
+    }
+    if pos < range.end {
+        return Err(io_err(
+            io::ErrorKind::InvalidInput,
+            "range beyond the end of the delta output",
+        ));
+    }
+    writer.finish()
+}
+
+fn copy_out_of_range() -> Error {
+    io_err(
+        io::ErrorKind::InvalidData,
+        "copy command beyond the end of the base file",
+    )
+}
+
+fn output_too_large() -> Error {
+    io_err(io::ErrorKind::InvalidData, "delta output too large")
+}
+
+// The output of a delta, described as a sequence of segments.
+struct OutputMap {
+    segments: Vec<Segment>,
+    literals: Vec<u8>,
+    len: u64,
+}
+
+// A part of the output of a delta, starting at `start`.
+struct Segment {
+    start: u64,
+    len: u64,
+    source: Source,
+}
+
+enum Source {
+    // copied from the base file, at the given offset
+    Base(u64),
+    // literal data, at the given offset in the literals buffer
+    Literal(usize),
+}
+
+impl OutputMap {
+    fn new<R: Read>(mut delta: DeltaReader<R>) -> Result<Self> {
+        let mut map = OutputMap {
+            segments: Vec::new(),
+            literals: Vec::new(),
+            len: 0,
+        };
+        while let Some(cmd) = delta.next_command()? {
+            let (len, source) = match cmd {
+                Command::Literal(len) => {
+                    let offset = map.literals.len();
+                    map.literals.extend(delta.read_literal(len)?);
+                    (len, Source::Literal(offset))
+                }
+                Command::Copy { offset, len } => (len, Source::Base(offset)),
+                Command::End => continue,
+            };
+            if len > 0 {
+                map.segments.push(Segment {
+                    start: map.len,
+                    len,
+                    source,
+                });
+                map.len = map.len.checked_add(len).ok_or_else(output_too_large)?;
+            }
+        }
+        Ok(map)
+    }
+
+    // Writes the operations producing the given range of the output.
+    fn copy<W: Write>(&self, offset: u64, len: u64, writer: &mut DeltaWriter<W>) -> Result<()> {
+        let end = offset
+            .checked_add(len)
+            .filter(|&end| end <= self.len)
+            .ok_or_else(|| {
+                io_err(
+                    io::ErrorKind::InvalidData,
+                    "copy command beyond the end of the intermediate file",
+                )
+            })?;
+        let first = self.segments.partition_point(|s| s.start + s.len <= offset);
+        let mut pos = offset;
+        for segment in &self.segments[first..] {
+            if pos >= end {
+                break;
+            }
+            let skip = pos - segment.start;
+            let n = (segment.len - skip).min(end - pos);
+            match segment.source {
+                Source::Base(base) => {
+                    let base = base.checked_add(skip).ok_or_else(copy_out_of_range)?;
+                    writer.copy(base, n)?
+                }
+                Source::Literal(lit) => {
+                    let lit = lit + skip as usize;
+                    writer.literal(&self.literals[lit..lit + n as usize])?
+                }
+            }
+            pos += n;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::{decode, encode};
+    use crate::{Op, whole};
+    use std::io::Cursor;
+
+    const A: &[u8] = b"0123456789abcdefghij";
+
+    // a minimal patch implementation, independent from librsync
+    fn apply(base: &[u8], delta: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for op in decode(delta).unwrap() {
+            match op {
+                Op::Literal(data) => out.extend(data),
+                Op::Copy { offset, len } => {
+                    out.extend_from_slice(&base[offset as usize..(offset + len) as usize])
+                }
+                Op::End => (),
+            }
+        }
+        out
+    }
+
+    fn composed(ab: &[u8], bc: &[u8]) -> Result<Vec<u8>> {
+        let mut ac = Vec::new();
+        let written = compose_deltas(&mut Cursor::new(ab), &mut Cursor::new(bc), &mut ac)?;
+        assert_eq!(written, ac.len() as u64);
+        Ok(ac)
+    }
+
+    #[test]
+    fn compose() {
+        let ab = encode(&[
+            Op::Copy { offset: 0, len: 10 },
+            Op::Literal(b"XYZ".to_vec()),
+            Op::Copy {
+                offset: 10,
+                len: 10,
+            },
+        ]);
+        let b = apply(A, &ab);
+        assert_eq!(b, b"0123456789XYZabcdefghij");
+
+        let bc = encode(&[
+            Op::Literal(b"<".to_vec()),
+            Op::Copy { offset: 5, len: 10 },
+            Op::Literal(b">".to_vec()),
+            Op::Copy { offset: 0, len: 2 },
+            Op::Copy { offset: 21, len: 2 },
+        ]);
+        let c = apply(&b, &bc);
+        assert_eq!(c, b"<56789XYZab>01ij");
+
+        let ac = composed(&ab, &bc).unwrap();
+        assert_eq!(apply(A, &ac), c);
+        assert_eq!(
+            decode(&ac).unwrap(),
+            vec![
+                Op::Literal(b"<".to_vec()),
+                Op::Copy { offset: 5, len: 5 },
+                Op::Literal(b"XYZ".to_vec()),
+                Op::Copy { offset: 10, len: 2 },
+                Op::Literal(b">".to_vec()),
+                Op::Copy { offset: 0, len: 2 },
+                Op::Copy { offset: 18, len: 2 },
+                Op::End,
+            ]
+        );
+    }
+
+    #[test]
+    fn optimize() {
+        #[rustfmt::skip]
+        let delta = [
+            0x72, 0x73, 0x02, 0x36,
+            // literal with a 1 byte length, followed by an immediate one
+            0x41, 0x03, b'a', b'b', b'c',
+            0x01, b'd',
+            // copy with 8 bytes parameters, followed by a contiguous one
+            0x54, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x05,
+            0x45, 0x05, 0x05,
+            // empty copy and literal
+            0x45, 0x0a, 0x00,
+            0x41, 0x00,
+            0x00,
+        ];
+        let mut optimized = Vec::new();
+        let written = optimize_delta(&mut Cursor::new(&delta[..]), &mut optimized).unwrap();
+        assert_eq!(written, optimized.len() as u64);
+        assert_eq!(
+            optimized,
+            [
+                0x72, 0x73, 0x02, 0x36, 0x04, b'a', b'b', b'c', b'd', 0x45, 0x00, 0x0a, 0x00
+            ]
+        );
+        assert_eq!(apply(A, &optimized), apply(A, &delta));
+
+        // an optimal delta is left as is
+        let mut again = Vec::new();
+        optimize_delta(&mut Cursor::new(&optimized), &mut again).unwrap();
+        assert_eq!(again, optimized);
+    }
+
+    #[test]
+    fn optimize_patch() {
+        #[rustfmt::skip]
+        let delta = [
+            0x72, 0x73, 0x02, 0x36,
+            0x41, 0x03, b'a', b'b', b'c',
+            0x01, b'd',
+            0x54, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x05,
+            0x45, 0x05, 0x05,
+            0x46, 0x0c, 0x00, 0x03,
+            0x00,
+        ];
+        let mut optimized = Vec::new();
+        optimize_delta(&mut Cursor::new(&delta[..]), &mut optimized).unwrap();
+        assert!(optimized.len() < delta.len());
+
+        // librsync gives the same output for both deltas
+        let mut expected = Vec::new();
+        whole::patch(
+            &mut Cursor::new(A),
+            &mut Cursor::new(&delta[..]),
+            &mut expected,
+        )
+        .unwrap();
+        let mut patched = Vec::new();
+        whole::patch(
+            &mut Cursor::new(A),
+            &mut Cursor::new(&optimized),
+            &mut patched,
+        )
+        .unwrap();
+        assert_eq!(expected, b"abcd0123456789cde");
+        assert_eq!(patched, expected);
+    }
+
+    #[test]
+    fn slice() {
+        let delta = encode(&[
+            Op::Copy { offset: 0, len: 10 },
+            Op::Literal(b"XYZ".to_vec()),
+            Op::Copy {
+                offset: 10,
+                len: 10,
+            },
+        ]);
+        let new = apply(A, &delta);
+        for start in 0..=new.len() as u64 {
+            for end in start..=new.len() as u64 {
+                let mut sliced = Vec::new();
+                let written =
+                    slice_delta(&mut Cursor::new(&delta), &mut sliced, start..end).unwrap();
+                assert_eq!(written, sliced.len() as u64);
+                assert_eq!(apply(A, &sliced), &new[start as usize..end as usize]);
+            }
+        }
+
+        let mut sliced = Vec::new();
+        slice_delta(&mut Cursor::new(&delta), &mut sliced, 8..12).unwrap();
+        assert_eq!(
+            decode(&sliced).unwrap(),
+            vec![
+                Op::Copy { offset: 8, len: 2 },
+                Op::Literal(b"XY".to_vec()),
+                Op::End,
+            ]
+        );
+
+        let res = slice_delta(&mut Cursor::new(&delta), &mut Vec::new(), 5..24);
+        assert!(res.is_err());
+        #[allow(clippy::reversed_empty_ranges)]
+        let res = slice_delta(&mut Cursor::new(&delta), &mut Vec::new(), 5..4);
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn slice_overflow() {
+        let delta = encode(&[Op::Copy {
+            offset: u64::MAX - 1,
+            len: 5,
+        }]);
+        let res = slice_delta(&mut Cursor::new(&delta), &mut Vec::new(), 2..4);
+        assert!(res.is_err());
+
+        let delta = encode(&[
+            Op::Copy {
+                offset: 0,
+                len: u64::MAX - 1,
+            },
+            Op::Copy { offset: 0, len: 5 },
+        ]);
+        let res = slice_delta(&mut Cursor::new(&delta), &mut Vec::new(), 1..u64::MAX);
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn compose_out_of_range() {
+        let ab = encode(&[Op::Copy { offset: 0, len: 10 }]);
+        let bc = encode(&[Op::Copy { offset: 5, len: 10 }]);
+        assert!(composed(&ab, &bc).is_err());
+    }
+
+    #[test]
+    fn compose_overflow() {
+        let ab = encode(&[Op::Copy {
+            offset: u64::MAX - 1,
+            len: 10,
+        }]);
+        let bc = encode(&[Op::Copy { offset: 5, len: 2 }]);
+        assert!(composed(&ab, &bc).is_err());
+
+        let ab = encode(&[
+            Op::Copy {
+                offset: 0,
+                len: u64::MAX,
+            },
+            Op::Copy { offset: 0, len: 1 },
+        ]);
+        assert!(composed(&ab, &bc).is_err());
+    }
+}
@@ -0,0 +1,676 @@
+//! Conversion between librsync deltas and VCDIFF deltas.
+//!
+//! VCDIFF is the generic delta format described by RFC 3284, and used by tools like xdelta3 and
+//! open-vcdiff. The `export` function converts a librsync delta to VCDIFF, so it can be applied by
+//! those tools, while the `import` function converts a VCDIFF delta to librsync format, so it can
+//! be applied by `Patch`.
+//!
+//! Exported deltas use the default code table and no compression. The output is split into
+//! windows of at most 4 MiB, each one with its own source segment, and copy addresses are encoded
+//! as absolute positions within that segment.
+//!
+//! Imported deltas can use any feature of the default code table, including the address caches
+//! and `RUN` instructions, but they must build their output only from the source file and from
+//! literal data. The following features are rejected with `Error::Unimplemented`:
+//!
+//! * secondary compression (`VCD_DECOMPRESS`) and compressed sections;
+//! * custom code tables (`VCD_CODETABLE`);
+//! * windows copying from the target file (`VCD_TARGET`);
+//! * copies from earlier parts of the current target window, including overlapping copies used
+//!   to encode repetitions.
+//!
+//! Application headers and the Adler-32 checksums of xdelta3 windows are skipped without being
+//! checked.
+
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+
+use crate::command::{Command, DeltaReader, DeltaWriter, truncated};
+use crate::{Error, Result, io_err};
+
+const MAGIC: [u8; 4] = [0xd6, 0xc3, 0xc4, 0x00];
+
+// header indicator bits
+const VCD_DECOMPRESS: u8 = 0x01;
+const VCD_CODETABLE: u8 = 0x02;
+const VCD_APPHEADER: u8 = 0x04;
+
+// window indicator bits
+const VCD_SOURCE: u8 = 0x01;
+const VCD_TARGET: u8 = 0x02;
+const VCD_ADLER32: u8 = 0x04;
+
+// instruction types
+const NOOP: u8 = 0;
+const ADD: u8 = 1;
+const RUN: u8 = 2;
+const COPY: u8 = 3;
+
+// sizes of the address caches of the default code table
+const NEAR_LEN: usize = 4;
+const SAME_LEN: usize = 3;
+
+// the maximum target length of exported windows
+const WINDOW_LEN: u64 = 1 << 22;
+
+// the maximum length of runs written at once while importing
+const RUN_CHUNK_LEN: u64 = 4096;
+
+/// Converts a librsync delta to a VCDIFF delta.
+///
+/// This function will consume the given delta stream and write its VCDIFF equivalent to the given
+/// output. In case of success, the number of bytes written is returned. The literal data of each
+/// window, up to 4 MiB, is kept in memory.
+pub fn export<R, W>(delta: &mut R, output: &mut W) -> Result<u64>
+where
+    R: Read + ?Sized,
+    W: Write + ?Sized,
+{
+    let mut reader = DeltaReader::new(delta)?;
+    let mut output = BufWriter::new(output);
+    output.write_all(&MAGIC)?;
+    output.write_all(&[0])?;
+    let mut written = MAGIC.len() as u64 + 1;
+    let mut window = Window::default();
+
+    while let Some(cmd) = reader.next_command()? {
+        let (mut offset, mut len) = match cmd {
+            Command::Literal(len) => (None, len),
+            Command::Copy { offset, len } => (Some(offset), len),
+            Command::End => continue,
+        };
+        if offset.is_some_and(|offset| offset.checked_add(len).is_none()) {
+            return Err(invalid("copy command beyond the end of the base file"));
+        }
+        // split the command across windows
+        while len > 0 {
+            let n = len.min(WINDOW_LEN - window.target_len);
+            match offset {
+                Some(ref mut offset) => {
+                    window.ops.push(WindowOp::Copy {
+                        offset: *offset,
+                        len: n,
+                    });
+                    *offset += n;
+                }
+                None => {
+                    window.data.extend(reader.read_literal(n)?);
+                    window.ops.push(WindowOp::Add(n));
+                }
+            }
+            window.target_len += n;
+            len -= n;
+            if window.target_len == WINDOW_LEN {
+                written += window.flush(&mut output)?;
+            }
+        }
+    }
+    if window.target_len > 0 {
+        written += window.flush(&mut output)?;
+    }
+    output.flush()?;
+    Ok(written)
+}
+
+/// Converts a VCDIFF delta to a librsync delta.
+///
+/// This function will consume the given VCDIFF stream and write its librsync equivalent to the
+/// given output. In case of success, the number of bytes written is returned. The sections of
+/// each window are kept in memory. See the module documentation for the supported features.
+pub fn import<R, W>(vcdiff: &mut R, output: &mut W) -> Result<u64>
+where
+    R: Read + ?Sized,
+    W: Write + ?Sized,
+{
+    let mut input = BufReader::new(vcdiff);
+    let mut magic = [0; 4];
+    input.read_exact(&mut magic).map_err(truncated)?;
+    if magic != MAGIC {
+        return Err(Error::BadMagic);
+    }
+    let indicator = read_byte(&mut input)?;
+    if indicator & (VCD_DECOMPRESS | VCD_CODETABLE) != 0 {
+        return Err(Error::Unimplemented);
+    }
+    if indicator & !VCD_APPHEADER != 0 {
+        return Err(invalid("unknown VCDIFF header indicator"));
+    }
+    if indicator & VCD_APPHEADER != 0 {
+        let len = read_varint(&mut input)?;
+        read_section(&mut input, len)?;
+    }
+
+    let table = code_table();
+    let mut writer = DeltaWriter::new(BufWriter::new(output))?;
+    while !input.fill_buf()?.is_empty() {
+        import_window(&mut input, &table, &mut writer)?;
+    }
+    writer.finish()
+}
+
+// The content of a window being exported.
+#[derive(Default)]
+struct Window {
+    target_len: u64,
+    data: Vec<u8>,
+    ops: Vec<WindowOp>,
+}
+
+enum WindowOp {
+    Add(u64),
+    Copy { offset: u64, len: u64 },
+}
+
+impl Window {
+    // Writes the window and clears it, returning the number of bytes written.
+    fn flush<W: Write>(&mut self, output: &mut W) -> Result<u64> {
+        // the source segment spans all the copies of the window
+        let copies = self.ops.iter().filter_map(|op| match *op {
+            WindowOp::Copy { offset, len } => Some((offset, offset + len)),
+            WindowOp::Add(_) => None,
+        });
+        let segment = copies.fold(None, |seg: Option<(u64, u64)>, (start, end)| match seg {
+            Some((s, e)) => Some((s.min(start), e.max(end))),
+            None => Some((start, end)),
+        });
+        let source_pos = segment.map_or(0, |(start, _)| start);
+
+        let mut insts = Vec::new();
+        let mut addrs = Vec::new();
+        for op in &self.ops {
+            match *op {
+                WindowOp::Add(len) => {
+                    if (1..=17).contains(&len) {
+                        insts.push(1 + len as u8);
+                    } else {
+                        insts.push(1);
+                        write_varint(&mut insts, len);
+                    }
+                }
+                WindowOp::Copy { offset, len } => {
+                    // copies with VCD_SELF addresses, whose codes start at 19
+                    if (4..=18).contains(&len) {
+                        insts.push(20 + (len - 4) as u8);
+                    } else {
+                        insts.push(19);
+                        write_varint(&mut insts, len);
+                    }
+                    write_varint(&mut addrs, offset - source_pos);
+                }
+            }
+        }
+
+        let mut encoding = Vec::new();
+        write_varint(&mut encoding, self.target_len);
+        encoding.push(0);
+        write_varint(&mut encoding, self.data.len() as u64);
+        write_varint(&mut encoding, insts.len() as u64);
+        write_varint(&mut encoding, addrs.len() as u64);
+        encoding.extend_from_slice(&self.data);
+        encoding.extend_from_slice(&insts);
+        encoding.extend_from_slice(&addrs);
+
+        let mut header = Vec::new();
+        match segment {
+            Some((start, end)) => {
+                header.push(VCD_SOURCE);
+                write_varint(&mut header, end - start);
+                write_varint(&mut header, start);
+            }
+            None => header.push(0),
+        }
+        write_varint(&mut header, encoding.len() as u64);
+        output.write_all(&header)?;
+        output.write_all(&encoding)?;
+
+        *self = Window::default();
+        Ok((header.len() + encoding.len()) as u64)
+    }
+}
+
+// An instruction of a code table.
+#[derive(Clone, Copy)]
+struct Inst {
+    kind: u8,
+    size: u8,
+    mode: u8,
+}
+
+impl Inst {
+    const fn new(kind: u8, size: u8, mode: u8) -> Self {
+        Inst { kind, size, mode }
+    }
+}
+
+// Builds the default code table, as described by section 5.6 of RFC 3284.
+fn code_table() -> [[Inst; 2]; 256] {
+    let noop = Inst::new(NOOP, 0, 0);
+    let mut table = [[noop; 2]; 256];
+    table[0][0] = Inst::new(RUN, 0, 0);
+    let mut i = 1;
+    for size in 0..=17 {
+        table[i][0] = Inst::new(ADD, size, 0);
+        i += 1;
+    }
+    for mode in 0..=8 {
+        table[i][0] = Inst::new(COPY, 0, mode);
+        i += 1;
+        for size in 4..=18 {
+            table[i][0] = Inst::new(COPY, size, mode);
+            i += 1;
+        }
+    }
+    for mode in 0..=5 {
+        for add_size in 1..=4 {
+            for copy_size in 4..=6 {
+                table[i] = [
+                    Inst::new(ADD, add_size, 0),
+                    Inst::new(COPY, copy_size, mode),
+                ];
+                i += 1;
+            }
+        }
+    }
+    for mode in 6..=8 {
+        for add_size in 1..=4 {
+            table[i] = [Inst::new(ADD, add_size, 0), Inst::new(COPY, 4, mode)];
+            i += 1;
+        }
+    }
+    for mode in 0..=8 {
+        table[i] = [Inst::new(COPY, 4, mode), Inst::new(ADD, 1, 0)];
+        i += 1;
+    }
+    debug_assert_eq!(i, 256);
+    table
+}
+
+// The address caches of the default code table.
+struct AddressCache {
+    near: [u64; NEAR_LEN],
+    next_slot: usize,
+    same: [u64; SAME_LEN * 256],
+}
+
+impl AddressCache {
+    fn new() -> Self {
+        AddressCache {
+            near: [0; NEAR_LEN],
+            next_slot: 0,
+            same: [0; SAME_LEN * 256],
+        }
+    }
+
+    // Decodes the address of a copy starting at `here`, encoded with the given mode.
+    fn decode(&mut self, here: u64, mode: u8, addrs: &mut &[u8]) -> Result<u64> {
+        let mode = usize::from(mode);
+        let addr = match mode {
+            0 => read_varint(addrs)?,
+            1 => here
+                .checked_sub(read_varint(addrs)?)
+                .ok_or_else(|| invalid("invalid VCDIFF address"))?,
+            m if m < 2 + NEAR_LEN => self.near[m - 2]
+                .checked_add(read_varint(addrs)?)
+                .ok_or_else(|| invalid("invalid VCDIFF address"))?,
+            m => self.same[(m - 2 - NEAR_LEN) * 256 + usize::from(read_byte(addrs)?)],
+        };
+        self.near[self.next_slot] = addr;
+        self.next_slot = (self.next_slot + 1) % NEAR_LEN;
+        self.same[(addr % (SAME_LEN as u64 * 256)) as usize] = addr;
+        Ok(addr)
+    }
+}
+
+fn import_window<R, W>(
+    input: &mut R,
+    table: &[[Inst; 2]; 256],
+    writer: &mut DeltaWriter<W>,
+) -> Result<()>
+where
+    R: Read + ?Sized,
+    W: Write,
+{
+    let indicator = read_byte(input)?;
+    if indicator & VCD_TARGET != 0 {
+        return Err(Error::Unimplemented);
+    }
+    if indicator & !(VCD_SOURCE | VCD_ADLER32) != 0 {
+        return Err(invalid("unknown VCDIFF window indicator"));
+    }
+    let (source_len, source_pos) = if indicator & VCD_SOURCE != 0 {
+        (read_varint(input)?, read_varint(input)?)
+    } else {
+        (0, 0)
+    };
+    if source_pos.checked_add(source_len).is_none() {
+        return Err(invalid(
+            "VCDIFF source segment beyond the end of the source file",
+        ));
+    }
+    read_varint(input)?; // length of the delta encoding
+    let target_len = read_varint(input)?;
+    if read_byte(input)? != 0 {
+        return Err(Error::Unimplemented);
+    }
+    let data_len = read_varint(input)?;
+    let insts_len = read_varint(input)?;
+    let addrs_len = read_varint(input)?;
+    if indicator & VCD_ADLER32 != 0 {
+        read_section(input, 4)?;
+    }
+    let data = read_section(input, data_len)?;
+    let insts = read_section(input, insts_len)?;
+    let addrs = read_section(input, addrs_len)?;
+
+    let mut data = &data[..];
+    let mut insts = &insts[..];
+    let mut addrs = &addrs[..];
+    let mut cache = AddressCache::new();
+    let mut pos = 0u64;
+    while !insts.is_empty() {
+        let code = read_byte(&mut insts)?;
+        for inst in table[usize::from(code)] {
+            if inst.kind == NOOP {
+                continue;
+            }
+            let size = match inst.size {
+                0 => read_varint(&mut insts)?,
+                size => u64::from(size),
+            };
+            if size > target_len - pos {
+                return Err(invalid("VCDIFF window longer than declared"));
+            }
+            match inst.kind {
+                ADD => writer.literal(split(&mut data, size)?)?,
+                RUN => {
+                    let byte = split(&mut data, 1)?[0];
+                    let chunk = [byte; RUN_CHUNK_LEN as usize];
+                    let mut left = size;
+                    while left > 0 {
+                        let n = left.min(RUN_CHUNK_LEN);
+                        writer.literal(&chunk[..n as usize])?;
+                        left -= n;
+                    }
+                }
+                _ => {
+                    let here = source_len
+                        .checked_add(pos)
+                        .ok_or_else(|| invalid("VCDIFF window too large"))?;
+                    let addr = cache.decode(here, inst.mode, &mut addrs)?;
+                    // only copies entirely within the source segment are supported
+                    if addr.checked_add(size).is_none_or(|end| end > source_len) {
+                        return Err(Error::Unimplemented);
+                    }
+                    let offset = source_pos
+                        .checked_add(addr)
+                        .ok_or_else(|| invalid("VCDIFF copy beyond the end of the source file"))?;
+                    writer.copy(offset, size)?;
+                }
+            }
+            pos += size;
+        }
+    }
+    if pos != target_len {
+        return Err(invalid("VCDIFF window shorter than declared"));
+    }
+    Ok(())
+}
+
+fn write_varint(output: &mut Vec<u8>, value: u64) {
+    let mut buf = [0; 10];
+    let mut i = buf.len() - 1;
+    buf[i] = (value & 0x7f) as u8;
+    let mut value = value >> 7;
+    while value > 0 {
+        i -= 1;
+        buf[i] = 0x80 | (value & 0x7f) as u8;
+        value >>= 7;
+    }
+    output.extend_from_slice(&buf[i..]);
+}
+
+fn read_varint<R: Read + ?Sized>(input: &mut R) -> Result<u64> {
+    let mut value = 0u64;
+    loop {
+        let byte = read_byte(input)?;
+        if value > u64::MAX >> 7 {
+            return Err(invalid("VCDIFF integer too large"));
+        }
+        value = (value << 7) | u64::from(byte & 0x7f);
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+}
+
+fn read_byte<R: Read + ?Sized>(input: &mut R) -> Result<u8> {
+    let mut byte = [0];
+    input.read_exact(&mut byte).map_err(truncated)?;
+    Ok(byte[0])
+}
+
+// Reads a whole section, without trusting its length for the allocation.
+fn read_section<R: Read + ?Sized>(input: &mut R, len: u64) -> Result<Vec<u8>> {
+    let mut section = Vec::new();
+    input.take(len).read_to_end(&mut section)?;
+    if (section.len() as u64) < len {
+        return Err(Error::Truncated);
+    }
+    Ok(section)
+}
+
+// Splits the first `len` bytes from a section.
+fn split<'a>(section: &mut &'a [u8], len: u64) -> Result<&'a [u8]> {
+    if len > section.len() as u64 {
+        return Err(invalid("VCDIFF section too short"));
+    }
+    let (head, tail) = section.split_at(len as usize);
+    *section = tail;
+    Ok(head)
+}
+
+fn invalid(msg: &str) -> Error {
+    io_err(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Op;
+    use crate::test_util::{decode, encode};
+    use std::io::Cursor;
+
+    fn imported(vcdiff: &[u8]) -> Result<Vec<u8>> {
+        let mut delta = Vec::new();
+        let written = import(&mut Cursor::new(vcdiff), &mut delta)?;
+        assert_eq!(written, delta.len() as u64);
+        Ok(delta)
+    }
+
+    fn exported(delta: &[u8]) -> Vec<u8> {
+        let mut vcdiff = Vec::new();
+        let written = export(&mut Cursor::new(delta), &mut vcdiff).unwrap();
+        assert_eq!(written, vcdiff.len() as u64);
+        vcdiff
+    }
+
+    #[test]
+    fn varint() {
+        for &value in &[0, 1, 127, 128, 16383, 16384, u64::from(u32::MAX), u64::MAX] {
+            let mut buf = Vec::new();
+            write_varint(&mut buf, value);
+            assert_eq!(read_varint(&mut &buf[..]).unwrap(), value);
+        }
+        let mut buf = Vec::new();
+        write_varint(&mut buf, 123_456_789);
+        assert_eq!(buf, [0xba, 0xef, 0x9a, 0x15]);
+        assert!(matches!(
+            read_varint(&mut &[0x80][..]),
+            Err(Error::Truncated)
+        ));
+        assert!(read_varint(&mut &[0xff; 11][..]).is_err());
+    }
+
+    #[test]
+    fn export_window() {
+        let delta = encode(&[
+            Op::Copy { offset: 5, len: 10 },
+            Op::Literal(b"abc".to_vec()),
+            Op::Copy { offset: 2, len: 1 },
+        ]);
+        #[rustfmt::skip]
+        let expected = [
+            0xd6, 0xc3, 0xc4, 0x00, 0x00,
+            // window with a source segment of 13 bytes at position 2
+            VCD_SOURCE, 13, 2, 14,
+            // target length, indicator and sections lengths
+            14, 0, 3, 4, 2,
+            b'a', b'b', b'c',
+            26, 4, 19, 1,
+            3, 0,
+        ];
+        assert_eq!(exported(&delta), expected);
+    }
+
+    #[test]
+    fn round_trip() {
+        let big_literal = vec![7; WINDOW_LEN as usize + 100];
+        let deltas = [
+            encode(&[]),
+            encode(&[Op::Literal(b"only literal data".to_vec())]),
+            encode(&[
+                Op::Copy { offset: 5, len: 10 },
+                Op::Literal(b"abc".to_vec()),
+                Op::Copy {
+                    offset: 1 << 40,
+                    len: 1000,
+                },
+                Op::Literal(vec![1; 300]),
+                Op::Copy { offset: 0, len: 4 },
+            ]),
+            encode(&[Op::Copy {
+                offset: 3,
+                len: 3 * WINDOW_LEN + 5,
+            }]),
+            encode(&[
+                Op::Copy { offset: 0, len: 20 },
+                Op::Literal(big_literal),
+                Op::Copy { offset: 0, len: 20 },
+            ]),
+        ];
+        for delta in &deltas {
+            assert_eq!(&imported(&exported(delta)).unwrap(), delta);
+        }
+    }
+
+    #[test]
+    fn import_address_modes() {
+        #[rustfmt::skip]
+        let vcdiff = [
+            0xd6, 0xc3, 0xc4, 0x00, 0x00,
+            // window with a source segment of 20 bytes at position 100
+            VCD_SOURCE, 20, 100, 18,
+            // target length, indicator and sections lengths
+            22, 0, 3, 6, 4,
+            b'a', b'b', b'z',
+            // COPY 5 (self), ADD 2 + COPY 4 (near 0), RUN 3, COPY 4 (same 0), COPY 4 (here)
+            21, 190, 0, 3, 116, 36,
+            2, 8, 10, 38,
+        ];
+        let delta = imported(&vcdiff).unwrap();
+        assert_eq!(
+            decode(&delta).unwrap(),
+            vec![
+                Op::Copy {
+                    offset: 102,
+                    len: 5
+                },
+                Op::Literal(b"ab".to_vec()),
+                Op::Copy {
+                    offset: 110,
+                    len: 4
+                },
+                Op::Literal(b"zzz".to_vec()),
+                Op::Copy {
+                    offset: 110,
+                    len: 4
+                },
+                Op::Copy {
+                    offset: 100,
+                    len: 4
+                },
+                Op::End,
+            ]
+        );
+    }
+
+    #[test]
+    fn import_skipped_extensions() {
+        #[rustfmt::skip]
+        let vcdiff = [
+            0xd6, 0xc3, 0xc4, 0x00, VCD_APPHEADER, 3, b'a', b'p', b'p',
+            VCD_ADLER32, 12,
+            2, 0, 2, 1, 0,
+            0xde, 0xad, 0xbe, 0xef,
+            b'h', b'i',
+            3,
+        ];
+        let delta = imported(&vcdiff).unwrap();
+        assert_eq!(
+            decode(&delta).unwrap(),
+            vec![Op::Literal(b"hi".to_vec()), Op::End]
+        );
+    }
+
+    #[test]
+    fn import_rejected() {
+        let header = [0xd6, 0xc3, 0xc4, 0x00, 0x00];
+        assert!(matches!(
+            imported(&[0xd6, 0xc3, 0xc4, 0x01, 0x00]),
+            Err(Error::BadMagic)
+        ));
+        assert!(matches!(imported(&header[..3]), Err(Error::Truncated)));
+        for indicator in [VCD_DECOMPRESS, VCD_CODETABLE] {
+            let res = imported(&[0xd6, 0xc3, 0xc4, 0x00, indicator]);
+            assert!(matches!(res, Err(Error::Unimplemented)));
+        }
+
+        // target window
+        let mut vcdiff = header.to_vec();
+        vcdiff.extend_from_slice(&[VCD_TARGET, 4, 0, 8, 4, 0, 0, 2, 1, 0x13, 0]);
+        assert!(matches!(imported(&vcdiff), Err(Error::Unimplemented)));
+
+        // copy from the target window
+        let mut vcdiff = header.to_vec();
+        #[rustfmt::skip]
+        vcdiff.extend_from_slice(&[
+            VCD_SOURCE, 4, 0, 9,
+            8, 0, 0, 2, 2,
+            20, 20,
+            0, 4,
+        ]);
+        assert!(matches!(imported(&vcdiff), Err(Error::Unimplemented)));
+
+        // compressed sections
+        let mut vcdiff = header.to_vec();
+        vcdiff.extend_from_slice(&[0, 5, 1, VCD_DECOMPRESS, 0, 0, 0]);
+        assert!(matches!(imported(&vcdiff), Err(Error::Unimplemented)));
+
+        // instructions longer than the window
+        let mut vcdiff = header.to_vec();
+        vcdiff.extend_from_slice(&[0, 8, 1, 0, 2, 1, 0, b'h', b'i', 3]);
+        assert!(imported(&vcdiff).is_err());
+
+        // source segment beyond the largest offset
+        let mut vcdiff = header.to_vec();
+        vcdiff.extend_from_slice(&[VCD_SOURCE, 10]);
+        write_varint(&mut vcdiff, u64::MAX);
+        vcdiff.extend_from_slice(&[8, 4, 0, 0, 1, 1, 20, 1]);
+        let res = imported(&vcdiff);
+        assert!(matches!(res, Err(Error::Io(ref e)) if e.kind() == io::ErrorKind::InvalidData));
+
+        // truncated window
+        let mut vcdiff = header.to_vec();
+        vcdiff.extend_from_slice(&[0, 7, 2, 0, 2, 1, 0, b'h']);
+        assert!(matches!(imported(&vcdiff), Err(Error::Truncated)));
+    }
+}
@@ -8,96 +8,402 @@
 //! `Patch` structs.
 
 use super::*;
-use std::io::{self, BufRead, Read, Seek, Write};
+use crate::command::{Command, DeltaWriter};
+use crate::compress::Decompressed;
+use crate::envelope::DeltaInput;
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, BufWriter, Read, Seek, SeekFrom, Write};
+use std::sync::{Mutex, mpsc};
+use std::thread;
+use std::time::Instant;
+
+// Approximate amount of input processed by each thread in a parallel signature.
+const PARALLEL_CHUNK_LEN: usize = 1 << 22;
+
+// Size of the buffer used to copy data from the base file, when generating reverse deltas.
+const REVERSE_BUF_LEN: usize = 1 << 16;
 
 /// Generates the signature of a basis input, and writes it out to an output stream.
 ///
 /// This function will consume the given input stream and attempt to write the resulting signature
-/// to the given output. In case of success, the number of bytes written is returned, otherwise
-/// an error is reported.
+/// to the given output. In case of success, the statistics of the job are returned, otherwise an
+/// error is reported.
 ///
-/// The accepted arguments, among the input and output streams, are:
-///
-/// * `block_len`: the block size for signature generation, in bytes;
-/// * `strong_len`: the truncated length of strong checksums, in bytes;
-/// * `sig_type`: the signature format to be used.
+/// The `opts` argument specifies the signature format, the block size and the truncated length of
+/// strong checksums to be used.
 pub fn signature_with_options<R, W>(
     input: &mut R,
     output: &mut W,
-    block_len: usize,
-    strong_len: usize,
-    sig_type: SignatureType,
-) -> Result<u64>
+    opts: SignatureOptions,
+) -> Result<Stats>
 where
     R: BufRead + ?Sized,
     W: Write + ?Sized,
 {
-    let mut sig = Signature::with_options(input, block_len, strong_len, sig_type)?;
-    let written = io::copy(&mut sig, output)?;
-    Ok(written)
+    let mut sig = Signature::with_options(input, opts)?;
+    io::copy(&mut sig, output)?;
+    Ok(sig.stats())
+}
+
+/// Generates the signature of a basis input by using multiple threads.
+///
+/// This function behaves like `signature_with_options`, and produces exactly the same output, but
+/// the checksums are computed by a pool of `threads` worker threads. The input is read
+/// sequentially by the calling thread and split into chunks aligned to the block length, which
+/// are hashed by the workers while the next ones are read. The statistics of all the chunks,
+/// merged together, are returned in case of success.
+///
+/// About twice `threads` chunks of a few megabytes each are kept in memory at the same time.
+pub fn signature_parallel<R, W>(
+    input: &mut R,
+    output: &mut W,
+    opts: SignatureOptions,
+    threads: usize,
+) -> Result<Stats>
+where
+    R: Read + ?Sized,
+    W: Write + ?Sized,
+{
+    let threads = threads.max(1);
+    let chunk_len = opts.block_len() * (PARALLEL_CHUNK_LEN / opts.block_len()).max(1);
+    let start = Instant::now();
+    let (chunk_tx, chunk_rx) = mpsc::sync_channel::<(usize, Vec<u8>)>(threads);
+    let (sig_tx, sig_rx) = mpsc::channel();
+    let chunk_rx = Arc::new(Mutex::new(chunk_rx));
+
+    let mut stats = thread::scope(move |scope| {
+        for _ in 0..threads {
+            let chunk_rx = chunk_rx.clone();
+            let sig_tx = sig_tx.clone();
+            scope.spawn(move || {
+                loop {
+                    // the lock is held only while waiting for the next chunk
+                    let next = match chunk_rx.lock() {
+                        Ok(rx) => rx.recv(),
+                        Err(_) => return,
+                    };
+                    let Ok((index, chunk)) = next else { return };
+                    let sig = chunk_signature(&chunk, opts);
+                    if sig_tx.send((index, sig)).is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+        // the workers hold the only receiver, so sending fails if they all stopped
+        drop(chunk_rx);
+        drop(sig_tx);
+
+        let mut writer = ChunkSigWriter::new(output);
+        let mut sent = 0;
+        let mut input_ended = false;
+        while !input_ended {
+            let mut chunk = vec![0; chunk_len];
+            let read = read_full(input, &mut chunk)?;
+            chunk.truncate(read);
+            input_ended = read < chunk_len;
+            // the first chunk is needed even if empty, to produce the signature header
+            if read > 0 || sent == 0 {
+                chunk_tx.send((sent, chunk)).map_err(|_| Error::Internal)?;
+                sent += 1;
+            }
+            while let Ok((index, sig)) = sig_rx.try_recv() {
+                writer.push(index, sig?)?;
+            }
+        }
+        drop(chunk_tx);
+        while writer.next < sent {
+            let (index, sig) = sig_rx.recv().map_err(|_| Error::Internal)?;
+            writer.push(index, sig?)?;
+        }
+        Ok::<_, Error>(Stats {
+            out_bytes: writer.written,
+            ..writer.stats
+        })
+    })?;
+    stats.elapsed = start.elapsed();
+    Ok(stats)
+}
+
+// Computes the signature of a chunk of a parallel signature.
+fn chunk_signature(chunk: &[u8], opts: SignatureOptions) -> Result<(Vec<u8>, Stats)> {
+    let mut sig = Signature::with_buf_read(chunk, opts)?;
+    let mut out = Vec::new();
+    sig.read_to_end(&mut out)?;
+    Ok((out, sig.stats()))
+}
+
+// Writes the signatures of the chunks of a parallel signature in order, keeping only the first
+// header.
+struct ChunkSigWriter<'a, W: ?Sized> {
+    output: &'a mut W,
+    pending: BTreeMap<usize, (Vec<u8>, Stats)>,
+    next: usize,
+    stats: Stats,
+    written: u64,
+}
+
+impl<'a, W: Write + ?Sized> ChunkSigWriter<'a, W> {
+    fn new(output: &'a mut W) -> Self {
+        ChunkSigWriter {
+            output,
+            pending: BTreeMap::new(),
+            next: 0,
+            stats: Stats::default(),
+            written: 0,
+        }
+    }
+
+    fn push(&mut self, index: usize, sig: (Vec<u8>, Stats)) -> Result<()> {
+        self.pending.insert(index, sig);
+        while let Some((sig, stats)) = self.pending.remove(&self.next) {
+            let data = if self.next == 0 {
+                &sig[..]
+            } else {
+                &sig[SignatureHeader::LEN..]
+            };
+            self.output.write_all(data)?;
+            self.stats.add(&stats);
+            self.written += data.len() as u64;
+            self.next += 1;
+        }
+        Ok(())
+    }
 }
 
 /// Generates the signature of a basis input, by using default settings.
 ///
 /// This function will consume the given input stream and attempt to write the resulting signature
-/// to the given output. In case of success, the number of bytes written is returned, otherwise
-/// an error is reported. Default settings are used to produce the signature. BLAKE2 for the
-/// hashing, 2048 bytes for the block length and full length for the strong signature size.
-pub fn signature<R, W>(input: &mut R, output: &mut W) -> Result<u64>
+/// to the given output. In case of success, the statistics of the job are returned, otherwise an
+/// error is reported. Default settings are used to produce the signature. BLAKE2 for the hashing,
+/// 2048 bytes for the block length and full length for the strong signature size.
+pub fn signature<R, W>(input: &mut R, output: &mut W) -> Result<Stats>
 where
     R: Read + ?Sized,
     W: Write + ?Sized,
 {
     let mut sig = Signature::new(input)?;
-    let written = io::copy(&mut sig, output)?;
-    Ok(written)
+    io::copy(&mut sig, output)?;
+    Ok(sig.stats())
 }
 
 /// Generates a delta between a signature and a new file streams.
 ///
 /// This function will consume the new file and base signature inputs and writes to the given
-/// output the delta between them. In case of success, the number of bytes written is returned,
+/// output the delta between them. In case of success, the statistics of the job are returned,
 /// otherwise an error is reported. The `new` parameter is the input stream representing a possibly
 /// modified file with respect to some base, for which its signature is provided as `base_sig`
 /// parameter.
 ///
 /// To generate a signature, see the `signature` function, or the `Signature` struct.
-pub fn delta<R, S, W>(new: &mut R, base_sig: &mut S, output: &mut W) -> Result<u64>
+pub fn delta<R, S, W>(new: &mut R, base_sig: &mut S, output: &mut W) -> Result<Stats>
 where
     R: Read + ?Sized,
     S: Read + ?Sized,
     W: Write + ?Sized,
 {
     let mut delta = Delta::new(new, base_sig)?;
-    let written = io::copy(&mut delta, output)?;
-    Ok(written)
+    io::copy(&mut delta, output)?;
+    Ok(delta.stats())
+}
+
+/// Generates a delta between a signature and a new file streams, with a fast path for files
+/// that are unchanged or only grew.
+///
+/// This function behaves like `delta`, but the leading blocks of the new file matching the ones
+/// of the base file are sent as a single copy command. See `Delta::with_fast_path`.
+pub fn delta_with_fast_path<R, S, W>(new: &mut R, base_sig: &mut S, output: &mut W) -> Result<Stats>
+where
+    R: Read + ?Sized,
+    S: Read + ?Sized,
+    W: Write + ?Sized,
+{
+    let mut delta = Delta::with_fast_path(new, base_sig)?;
+    io::copy(&mut delta, output)?;
+    Ok(delta.stats())
+}
+
+/// Generates a delta between two files, without a separate signature step.
+///
+/// This function will consume the base and new file inputs and writes to the given output the
+/// delta between them. In case of success, the statistics of the job are returned, otherwise an
+/// error is reported. The signature of the base file is only kept in memory. See
+/// `Delta::from_files` for the meaning of `opts`.
+pub fn delta_from_files<B, R, W>(
+    base: &mut B,
+    new: &mut R,
+    output: &mut W,
+    opts: Option<SignatureOptions>,
+) -> Result<Stats>
+where
+    B: Read + Seek + ?Sized,
+    R: Read + ?Sized,
+    W: Write + ?Sized,
+{
+    let mut delta = Delta::from_files(base, new, opts)?;
+    io::copy(&mut delta, output)?;
+    Ok(delta.stats())
+}
+
+/// Estimates the size of the delta between a signature and a new file streams.
+///
+/// This function behaves like `delta`, but instead of writing the delta it returns its projected
+/// size, together with the amount of literal and copied data. See `Delta::estimate`.
+pub fn delta_estimate<R, S>(new: &mut R, base_sig: &mut S) -> Result<DeltaEstimate>
+where
+    R: Read + ?Sized,
+    S: Read + ?Sized,
+{
+    Delta::new(new, base_sig)?.estimate()
 }
 
 /// Applies a patch, relative to a basis, into an output stream.
 ///
 /// This function will consume the base file and the new file delta inputs and writes to the given
-/// output the patched input. In case of success, the number of bytes written is returned,
+/// output the patched input. In case of success, the statistics of the job are returned,
 /// otherwise an error is reported. The `base` parameter is the input stream representing the base
 /// file from which apply the patch. This stream must be seekable. The `delta` parameter is a
 /// stream containing the delta between the base file and the new one. The output parameter will
 /// be used to write the output.
 ///
 /// To generate a delta, see the `delta` function, or the `Delta` struct.
-pub fn patch<B, D, W>(base: &mut B, delta: &mut D, output: &mut W) -> Result<u64>
+pub fn patch<B, D, W>(base: &mut B, delta: &mut D, output: &mut W) -> Result<Stats>
 where
     B: Read + Seek + ?Sized,
     D: Read + ?Sized,
     W: Write + ?Sized,
 {
     let mut patch = Patch::new(base, delta)?;
-    let written = io::copy(&mut patch, output)?;
-    Ok(written)
+    io::copy(&mut patch, output)?;
+    Ok(patch.stats())
+}
+
+/// The statistics of a patch producing a reverse delta.
+///
+/// See `patch_with_reverse`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ReverseStats {
+    /// The statistics of the patch. Only the number of commands and bytes are reported.
+    pub patch: Stats,
+    /// The statistics of the reverse delta. Only the number of bytes are reported.
+    pub reverse: Stats,
+}
+
+/// Applies a patch, and generates at the same time the reverse delta.
+///
+/// This function behaves like `patch`, writing to `output` the new file obtained by applying
+/// `delta` to `base`. In addition, it writes to `reverse` a delta that, applied to the new file,
+/// gives back `base`. This allows to keep only the latest version of a file, together with small
+/// reverse deltas for the previous ones. In case of success, the statistics of the patch and of
+/// the reverse delta are returned.
+///
+/// Like `Patch`, compressed and enveloped deltas are accepted. The commands of the delta are
+/// applied directly, and the ranges of `base` reused by its copy commands become copy commands of
+/// the reverse delta. The rest of `base` is stored as literal data, read back once the patch is
+/// complete.
+pub fn patch_with_reverse<B, D, W, V>(
+    base: &mut B,
+    delta: &mut D,
+    output: &mut W,
+    reverse: &mut V,
+) -> Result<ReverseStats>
+where
+    B: Read + Seek + ?Sized,
+    D: Read + ?Sized,
+    W: Write + ?Sized,
+    V: Write + ?Sized,
+{
+    let start = Instant::now();
+    let input = DeltaInput::new(Decompressed::new(BufReader::new(delta))?)?;
+    let mut reader = DeltaReader::new(input)?;
+    let mut output = BufWriter::new(output);
+    let mut stats = ReverseStats::default();
+
+    // apply the delta, recording the copied ranges as (base offset, new offset, len)
+    let mut copies = Vec::new();
+    let mut new_len = 0u64;
+    let mut buf = vec![0; REVERSE_BUF_LEN];
+    while let Some(cmd) = reader.next_command()? {
+        match cmd {
+            Command::Literal(len) => {
+                let data = reader.read_literal(len)?;
+                output.write_all(&data)?;
+                reader.get_mut().check_output(&data, false)?;
+                stats.patch.lit_cmds += 1;
+                stats.patch.lit_bytes += len;
+                new_len += len;
+            }
+            Command::Copy { offset, len } => {
+                base.seek(SeekFrom::Start(offset))?;
+                let mut left = len;
+                while left > 0 {
+                    let want = left.min(REVERSE_BUF_LEN as u64) as usize;
+                    if read_full(base, &mut buf[..want])? < want {
+                        return Err(io_err(
+                            io::ErrorKind::InvalidData,
+                            "copy command beyond the end of the base file",
+                        ));
+                    }
+                    output.write_all(&buf[..want])?;
+                    reader.get_mut().check_output(&buf[..want], false)?;
+                    left -= want as u64;
+                }
+                if len > 0 {
+                    copies.push((offset, new_len, len));
+                }
+                stats.patch.copy_cmds += 1;
+                stats.patch.copy_bytes += len;
+                new_len += len;
+            }
+            Command::End => (),
+        }
+    }
+    reader.get_mut().check_output(&[], true)?;
+    output.flush()?;
+    stats.patch.out_bytes = new_len;
+    stats.patch.elapsed = start.elapsed();
+
+    // cover the base with the longest copies available, and literals elsewhere
+    copies.sort_unstable();
+    let base_len = base.seek(SeekFrom::End(0))?;
+    let mut writer = DeltaWriter::new(BufWriter::new(reverse))?;
+    let mut next = 0;
+    let mut best: Option<(u64, u64, u64)> = None;
+    let mut pos = 0;
+    while pos < base_len {
+        while next < copies.len() && copies[next].0 <= pos {
+            let (start, _, len) = copies[next];
+            if best.is_none_or(|(b_start, _, b_len)| start + len > b_start + b_len) {
+                best = Some(copies[next]);
+            }
+            next += 1;
+        }
+        match best {
+            Some((start, new_off, len)) if start + len > pos => {
+                writer.copy(new_off + pos - start, start + len - pos)?;
+                stats.reverse.copy_bytes += start + len - pos;
+                pos = start + len;
+            }
+            _ => {
+                let end = copies.get(next).map_or(base_len, |c| c.0.min(base_len));
+                base.seek(SeekFrom::Start(pos))?;
+                writer.literal_from(base, end - pos)?;
+                stats.reverse.lit_bytes += end - pos;
+                pos = end;
+            }
+        }
+    }
+    stats.reverse.in_bytes = base_len;
+    stats.reverse.out_bytes = writer.finish()?;
+    stats.reverse.elapsed = start.elapsed();
+    Ok(stats)
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::SignatureType;
+    use crate::{SignatureOptions, SignatureType};
 
     use std::io::Cursor;
     use std::str::from_utf8;
@@ -109,25 +415,326 @@ mod test {
     fn integration() {
         // signature
         let mut sig = Vec::new();
-        signature_with_options(
-            &mut Cursor::new(DATA),
-            &mut sig,
-            10,
-            5,
-            SignatureType::Blake2,
-        )
-        .unwrap();
+        let opts = SignatureOptions::builder(SignatureType::Blake2)
+            .block_len(10)
+            .strong_len(5)
+            .build()
+            .unwrap();
+        signature_with_options(&mut Cursor::new(DATA), &mut sig, opts).unwrap();
 
         // delta
         let mut dlt = Vec::new();
-        delta(&mut Cursor::new(DATA2), &mut Cursor::new(sig), &mut dlt).unwrap();
+        let stats = delta(&mut Cursor::new(DATA2), &mut Cursor::new(sig), &mut dlt).unwrap();
+        assert_eq!(stats.out_bytes, dlt.len() as u64);
 
         // patch
         let mut out = Vec::new();
-        patch(&mut Cursor::new(DATA), &mut Cursor::new(dlt), &mut out).unwrap();
+        let stats = patch(&mut Cursor::new(DATA), &mut Cursor::new(dlt), &mut out).unwrap();
+        assert_eq!(stats.out_bytes, out.len() as u64);
 
         // check that patched version is the same as DATA2
         let out_str = from_utf8(&out).unwrap();
         assert_eq!(out_str, DATA2);
     }
+
+    #[test]
+    fn fast_path() {
+        let base = DATA.repeat(100);
+        let opts = SignatureOptions::builder(SignatureType::Blake2)
+            .block_len(64)
+            .build()
+            .unwrap();
+        let mut sig = Vec::new();
+        signature_with_options(&mut Cursor::new(&base), &mut sig, opts).unwrap();
+
+        let grown = base.clone() + DATA2;
+        let modified = DATA2.to_string() + &base;
+        for new in [&base, &grown, &modified] {
+            let mut dlt = Vec::new();
+            let stats =
+                delta_with_fast_path(&mut Cursor::new(new), &mut Cursor::new(&sig), &mut dlt)
+                    .unwrap();
+            assert_eq!(stats.out_bytes, dlt.len() as u64);
+            let mut out = Vec::new();
+            patch(&mut Cursor::new(&base), &mut Cursor::new(&dlt), &mut out).unwrap();
+            assert!(out == new.as_bytes());
+
+            let ops: Vec<_> = DeltaReader::new(Cursor::new(&dlt))
+                .unwrap()
+                .map(|op| op.unwrap())
+                .collect();
+            // the last block of the base is partial, and sent as literal when the file grows
+            let prefix = (base.len() / 64 * 64) as u64;
+            assert!(prefix < base.len() as u64);
+            if new == &base {
+                assert_eq!(
+                    ops,
+                    vec![
+                        Op::Copy {
+                            offset: 0,
+                            len: base.len() as u64
+                        },
+                        Op::End
+                    ]
+                );
+            } else if new == &grown {
+                assert_eq!(
+                    ops,
+                    vec![
+                        Op::Copy {
+                            offset: 0,
+                            len: prefix
+                        },
+                        Op::Literal(grown.as_bytes()[prefix as usize..].to_vec()),
+                        Op::End
+                    ]
+                );
+            }
+        }
+    }
+
+    #[cfg(any(feature = "zstd", feature = "gzip"))]
+    #[test]
+    fn fast_path_compressed_signature() {
+        let base = DATA.repeat(100);
+        let opts = SignatureOptions::builder(SignatureType::Blake2)
+            .block_len(64)
+            .build()
+            .unwrap();
+        #[cfg(feature = "gzip")]
+        let method = Compression::Gzip(6);
+        #[cfg(not(feature = "gzip"))]
+        let method = Compression::Zstd(3);
+        let mut sig = Vec::new();
+        let job = Signature::with_options(Cursor::new(&base), opts).unwrap();
+        Compressed::new(job, method)
+            .unwrap()
+            .read_to_end(&mut sig)
+            .unwrap();
+
+        let mut dlt = Vec::new();
+        delta_with_fast_path(&mut Cursor::new(&base), &mut Cursor::new(&sig), &mut dlt).unwrap();
+        let ops: Vec<_> = DeltaReader::new(Cursor::new(&dlt))
+            .unwrap()
+            .map(|op| op.unwrap())
+            .collect();
+        assert_eq!(
+            ops,
+            vec![
+                Op::Copy {
+                    offset: 0,
+                    len: base.len() as u64
+                },
+                Op::End
+            ]
+        );
+    }
+
+    #[test]
+    fn local_delta() {
+        let mut dlt = Vec::new();
+        let base = DATA.repeat(100);
+        let new = DATA2.repeat(100);
+        delta_from_files(
+            &mut Cursor::new(&base),
+            &mut Cursor::new(&new),
+            &mut dlt,
+            None,
+        )
+        .unwrap();
+        let mut out = Vec::new();
+        patch(&mut Cursor::new(&base), &mut Cursor::new(dlt), &mut out).unwrap();
+        assert!(out == new.as_bytes());
+    }
+
+    #[test]
+    fn reverse_delta() {
+        let base = b"0123456789abcdefghijklmnopqrstuvwxyz";
+        let mut writer = DeltaWriter::new(Vec::new()).unwrap();
+        writer.literal(b"new ").unwrap();
+        writer.copy(20, 10).unwrap();
+        writer.copy(5, 10).unwrap();
+        writer.literal(b" end").unwrap();
+        writer.copy(8, 4).unwrap();
+        writer.finish().unwrap();
+        let dlt = writer.into_inner();
+
+        let mut new = Vec::new();
+        let mut rev = Vec::new();
+        let stats = patch_with_reverse(
+            &mut Cursor::new(base),
+            &mut Cursor::new(&dlt),
+            &mut new,
+            &mut rev,
+        )
+        .unwrap();
+        assert_eq!(new, b"new klmnopqrst56789abcde end89ab");
+        assert_eq!(stats.patch.out_bytes, new.len() as u64);
+        assert_eq!(stats.patch.lit_bytes, 8);
+        assert_eq!(stats.patch.copy_bytes, 24);
+        assert_eq!(stats.reverse.in_bytes, base.len() as u64);
+        assert_eq!(stats.reverse.out_bytes, rev.len() as u64);
+        assert_eq!(stats.reverse.copy_bytes, 20);
+        assert_eq!(stats.reverse.lit_bytes, 16);
+
+        let ops: Vec<_> = DeltaReader::new(Cursor::new(&rev))
+            .unwrap()
+            .map(|op| op.unwrap())
+            .collect();
+        assert_eq!(
+            ops,
+            vec![
+                Op::Literal(b"01234".to_vec()),
+                Op::Copy {
+                    offset: 14,
+                    len: 10
+                },
+                Op::Literal(b"fghij".to_vec()),
+                Op::Copy { offset: 4, len: 10 },
+                Op::Literal(b"uvwxyz".to_vec()),
+                Op::End,
+            ]
+        );
+
+        // the reverse delta gives back the base
+        let mut out = Vec::new();
+        patch_with_reverse(
+            &mut Cursor::new(&new),
+            &mut Cursor::new(&rev),
+            &mut out,
+            &mut Vec::new(),
+        )
+        .unwrap();
+        assert_eq!(out, base);
+
+        // copies beyond the end of the base
+        let mut writer = DeltaWriter::new(Vec::new()).unwrap();
+        writer.copy(30, 10).unwrap();
+        writer.finish().unwrap();
+        let dlt = writer.into_inner();
+        let res = patch_with_reverse(
+            &mut Cursor::new(base),
+            &mut Cursor::new(dlt),
+            &mut Vec::new(),
+            &mut Vec::new(),
+        );
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn reverse_delta_envelope() {
+        let base = DATA.repeat(10);
+        let new = base.clone() + DATA2;
+        let opts = SignatureOptions::builder(SignatureType::Blake2)
+            .block_len(DATA.len())
+            .build()
+            .unwrap();
+        let mut sig = Vec::new();
+        signature_with_options(&mut Cursor::new(&base), &mut sig, opts).unwrap();
+        let sig = Arc::new(LoadedSignature::new(&mut Cursor::new(sig)).unwrap());
+        let mut dlt = Vec::new();
+        Delta::with_envelope(Cursor::new(&new), sig)
+            .unwrap()
+            .read_to_end(&mut dlt)
+            .unwrap();
+
+        let mut out = Vec::new();
+        let mut rev = Vec::new();
+        patch_with_reverse(
+            &mut Cursor::new(&base),
+            &mut Cursor::new(&dlt),
+            &mut out,
+            &mut rev,
+        )
+        .unwrap();
+        assert!(out == new.as_bytes());
+        let mut back = Vec::new();
+        patch(&mut Cursor::new(&out), &mut Cursor::new(&rev), &mut back).unwrap();
+        assert!(back == base.as_bytes());
+
+        // the envelope detects a wrong base file
+        let res = patch_with_reverse(
+            &mut Cursor::new(base.to_uppercase()),
+            &mut Cursor::new(&dlt),
+            &mut Vec::new(),
+            &mut Vec::new(),
+        );
+        assert!(matches!(res, Err(Error::ChecksumMismatch)));
+    }
+
+    #[cfg(any(feature = "zstd", feature = "gzip"))]
+    #[test]
+    fn reverse_delta_compressed() {
+        let base = b"0123456789abcdefghijklmnopqrstuvwxyz";
+        let mut writer = DeltaWriter::new(Vec::new()).unwrap();
+        writer.literal(b"new ").unwrap();
+        writer.copy(20, 10).unwrap();
+        writer.finish().unwrap();
+        let plain = writer.into_inner();
+        #[cfg(feature = "gzip")]
+        let method = Compression::Gzip(6);
+        #[cfg(not(feature = "gzip"))]
+        let method = Compression::Zstd(3);
+        let mut dlt = Vec::new();
+        Compressed::new(&plain[..], method)
+            .unwrap()
+            .read_to_end(&mut dlt)
+            .unwrap();
+
+        let mut out = Vec::new();
+        let mut rev = Vec::new();
+        patch_with_reverse(
+            &mut Cursor::new(base),
+            &mut Cursor::new(&dlt),
+            &mut out,
+            &mut rev,
+        )
+        .unwrap();
+        assert_eq!(out, b"new klmnopqrst");
+        let mut back = Vec::new();
+        patch_with_reverse(
+            &mut Cursor::new(&out),
+            &mut Cursor::new(&rev),
+            &mut back,
+            &mut Vec::new(),
+        )
+        .unwrap();
+        assert_eq!(back, base);
+    }
+
+    #[test]
+    fn parallel_signature() {
+        // pseudo-random data spanning multiple chunks, with a partial last block
+        let mut seed = 42u32;
+        let data: Vec<u8> = (0..2 * PARALLEL_CHUNK_LEN + 12345)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (seed >> 16) as u8
+            })
+            .collect();
+        for &sig_type in &[SignatureType::MD4, SignatureType::Blake2] {
+            let opts = SignatureOptions::builder(sig_type)
+                .block_len(1000)
+                .build()
+                .unwrap();
+            let mut expected = Vec::new();
+            signature_with_options(&mut Cursor::new(&data), &mut expected, opts).unwrap();
+            for &threads in &[1, 2, 4] {
+                let mut sig = Vec::new();
+                let stats =
+                    signature_parallel(&mut Cursor::new(&data), &mut sig, opts, threads).unwrap();
+                assert_eq!(stats.out_bytes, sig.len() as u64);
+                assert_eq!(stats.in_bytes, data.len() as u64);
+                assert!(sig == expected);
+            }
+        }
+
+        // empty input
+        let mut expected = Vec::new();
+        let opts = SignatureOptions::default();
+        signature_with_options(&mut Cursor::new(&[]), &mut expected, opts).unwrap();
+        let mut sig = Vec::new();
+        signature_parallel(&mut Cursor::new(&[]), &mut sig, opts, 2).unwrap();
+        assert_eq!(sig, expected);
+    }
 }